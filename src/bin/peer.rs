@@ -1,6 +1,10 @@
 use swapbytes::ui::page::landing::Landing;
-use swapbytes::{network::network, ui::router::Router};
+use swapbytes::{config::Config, daemon::{self, SOCKET_FILE}, history::History, network::{identity, network}, state::STATE, ui::router::Router, util};
+use libp2p::identity::Keypair;
+use libp2p::Multiaddr;
 use std::io as std_out;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
 use tokio::spawn;
 use std::error::Error;
 use log::info;
@@ -18,11 +22,15 @@ use ratatui::{
 };
 
 
+/// Name of the log file (inside the data directory).
+const LOG_FILE: &str = "peer.log";
+
+
 /// Setup a global logger for the application. As Ratatui UI replaces the terminal, we need a place to safely log information.
-/// Logs can be found in "peer.log"
+/// Logs can be found in "peer.log" in the data directory.
 fn setup_logger() -> Result<(), Box<dyn Error>> {
 
-    let log_file = File::create("src/log/peer.log")?;
+    let log_file = File::create(util::data_dir().join(LOG_FILE))?;
     Dispatch::new()
         .filter(|metadata| metadata.level() <= log::LevelFilter::Info)
         .format(|out, message, record| {
@@ -41,13 +49,187 @@ fn setup_logger() -> Result<(), Box<dyn Error>> {
 }
 
 
+/// Command line arguments. Running without a subcommand starts the application.
+#[derive(Parser)]
+#[command(name = "swapbytes", version, about = "A peer to peer chat and file sharing application for trading study notes")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    /// Directory for this peer's identity, config, logs, downloads and history (defaults to $XDG_DATA_HOME/swapbytes).
+    /// Give each peer its own to run several side by side
+    #[arg(long, global = true, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+
+    /// Path to a JSON config file (defaults to config.json in the data directory)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Multiaddr to listen on, eg. /ip4/0.0.0.0/tcp/4001. Can be repeated, and replaces the configured addresses
+    #[arg(long = "listen", value_name = "MULTIADDR")]
+    listen: Vec<Multiaddr>,
+
+    /// Multiaddr of a peer to connect to on startup, eg. /ip4/10.0.0.5/tcp/4001/p2p/<PeerId>. Can be repeated
+    #[arg(long = "connect", value_name = "MULTIADDR")]
+    connect: Vec<Multiaddr>,
+
+    /// Multiaddr of a rendezvous node to discover peers through, ending in /p2p/<PeerId>
+    #[arg(long, value_name = "MULTIADDR")]
+    rendezvous: Option<Multiaddr>,
+
+    /// Namespace to register under at the rendezvous node, eg. a course code
+    #[arg(long)]
+    namespace: Option<String>,
+
+    /// Run without the UI, controlled through a JSON API on a Unix socket
+    #[arg(long, requires = "nickname")]
+    headless: bool,
+
+    /// Nickname to use when running headless
+    #[arg(long)]
+    nickname: Option<String>,
+
+    /// Directory to save received files to (defaults to downloads in the data directory)
+    #[arg(long, value_name = "PATH")]
+    downloads: Option<PathBuf>,
+
+    /// Path of the control socket when running headless (defaults to swapbytes.sock in the data directory)
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+}
+
+
+impl Cli {
+
+    /// Loads the config file and applies any overrides given on the command line.
+    fn load_config(&self) -> Result<Config, Box<dyn Error>> {
+
+        let path = self.config.clone().unwrap_or_else(|| Config::default_path(&util::data_dir()));
+        let mut config = Config::load(&path)?;
+
+        if !self.listen.is_empty() {
+            config.listen_addresses = self.listen.iter().map(|addr| addr.to_string()).collect();
+        }
+
+        config.bootstrap_peers.extend(self.connect.iter().map(|addr| addr.to_string()));
+
+        if let Some(rendezvous) = &self.rendezvous {
+            config.rendezvous_point = Some(rendezvous.to_string());
+        }
+
+        if let Some(namespace) = &self.namespace {
+            config.rendezvous_namespace = namespace.clone();
+        }
+
+        if let Some(downloads) = &self.downloads {
+            config.downloads_dir = Some(downloads.clone());
+        }
+
+        Ok(config)
+    }
+}
+
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Manage the persistent peer identity (keypair) kept in the data directory
+    #[command(subcommand)]
+    Identity(IdentityCommand),
+}
+
+
+#[derive(Subcommand)]
+enum IdentityCommand {
+    /// Generate a new identity. Use --force to replace an existing one (your PeerId will change!)
+    Generate {
+        #[arg(long)]
+        force: bool,
+    },
+    /// Export the current identity to a file
+    Export {
+        path: PathBuf,
+    },
+    /// Import an identity from a file, replacing the current one
+    Import {
+        path: PathBuf,
+    },
+    /// Print the PeerId of the current identity
+    Show,
+}
+
+
+/// Runs an identity subcommand and prints the result to the terminal.
+fn run_identity_command(command: IdentityCommand) -> Result<(), Box<dyn Error>> {
+
+    let data_dir = util::data_dir();
+
+    let keypair = match command {
+        IdentityCommand::Generate { force } => identity::generate(&data_dir, force)?,
+        IdentityCommand::Export { path } => identity::export(&data_dir, &path)?,
+        IdentityCommand::Import { path } => identity::import(&data_dir, &path)?,
+        IdentityCommand::Show => identity::load_or_generate(&data_dir)?,
+    };
+
+    println!("{}", keypair.public().to_peer_id());
+    Ok(())
+}
+
+
+/// Runs the peer without the UI, serving the control API on a Unix socket (see daemon.rs) until interrupted.
+async fn run_headless(cli: &Cli, keypair: Keypair, config: &Config) -> Result<(), Box<dyn Error>> {
+
+    STATE.lock().unwrap().nickname = cli.nickname.clone().unwrap_or_default();
+
+    let (client, event_loop) = network::new(keypair, config)?;
+    spawn(event_loop.run(client.clone()));
+
+    let socket = cli.socket.clone().unwrap_or_else(|| util::data_dir().join(SOCKET_FILE));
+    println!("{} running headless, control socket at {}", STATE.lock().unwrap().peer_id, socket.display());
+
+    let result = tokio::select! {
+        result = daemon::serve(&socket, client) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    let _ = std::fs::remove_file(&socket);
+    result
+}
+
+
 /// Main entry point of the application.
 /// A chat and file sharing application designed for students to organise the trading of class notes.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+
+    let cli = Cli::parse();
+
+    if let Some(data_dir) = &cli.data_dir {
+        util::set_data_dir(data_dir.clone());
+    }
+    std::fs::create_dir_all(util::data_dir())?;
+
+    if let Some(CliCommand::Identity(command)) = cli.command {
+        return run_identity_command(command);
+    }
+
+    let config = cli.load_config()?;
+    if let Some(downloads) = &config.downloads_dir {
+        util::set_downloads_dir(downloads.clone());
+    }
     
     setup_logger().expect("Logger setup failed");
 
+    // Load (or create on first launch) our persistent identity
+    let keypair = identity::load_or_generate(&util::data_dir())?;
+
+    // Chat history from previous sessions
+    let history = History::open(&util::data_dir(), config.history_limit)?;
+    STATE.lock().unwrap().load_history(history)?;
+
+    if cli.headless {
+        return run_headless(&cli, keypair, &config).await;
+    }
+
     // Ratatui UI Setup
     enable_raw_mode()?;
     std_out::stdout().execute(EnterAlternateScreen)?;
@@ -55,12 +237,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut tab_manager = Router::default();
     let mut landing = Landing::default();
 
-    // Libp2p Setup
-    let (mut client, event_loop) = network::new()?;    
-
     // Page to enter nickname
     while !landing.run(&mut terminal).await? {}
 
+    // Libp2p Setup (after the nickname is known, as it is shared with peers on connection)
+    let (mut client, event_loop) = network::new(keypair, &config)?;
+
     // Enter the Application
     spawn(event_loop.run(client.clone()));
     
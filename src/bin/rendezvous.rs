@@ -0,0 +1,121 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+use chrono::Local;
+use clap::Parser;
+use fern::Dispatch;
+use futures::StreamExt;
+use libp2p::{identify, noise, ping, rendezvous, swarm::{NetworkBehaviour, SwarmEvent}, tcp, yamux, Multiaddr};
+use swapbytes::{network::identity, util};
+
+
+/// A rendezvous node for class-wide discovery. Peers register with it under a namespace (eg. a course code) and discover
+/// every other peer registered under the same namespace, so students don't need to be on the same network segment.
+#[derive(Parser)]
+#[command(name = "swapbytes-rendezvous", version, about = "A rendezvous node for Swapbytes peers to discover each other")]
+struct Cli {
+    /// Multiaddr to listen on. Can be repeated. Use a fixed port so peers can be given a well-known address
+    #[arg(long = "listen", value_name = "MULTIADDR", default_values = ["/ip4/0.0.0.0/tcp/62649", "/ip4/0.0.0.0/udp/62649/quic-v1"])]
+    listen: Vec<Multiaddr>,
+
+    /// Data directory to keep the node's identity in (under "rendezvous", defaults to $XDG_DATA_HOME/swapbytes)
+    #[arg(long, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+}
+
+
+/// Behaviour of the rendezvous node. Identify lets registering peers learn the address we observe them on, and ping
+/// keeps connections from going idle.
+#[derive(NetworkBehaviour)]
+struct RendezvousBehaviour {
+    rendezvous: rendezvous::server::Behaviour,
+    identify: identify::Behaviour,
+    ping: ping::Behaviour,
+}
+
+
+/// Logs to the terminal, as unlike a peer there is no UI to get in the way.
+fn setup_logger() -> Result<(), Box<dyn Error>> {
+
+    Dispatch::new()
+        .filter(|metadata| metadata.level() <= log::LevelFilter::Info)
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{} [{}] - {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                message
+            ))
+        })
+        .chain(std::io::stdout())
+        .apply()?;
+
+    Ok(())
+}
+
+
+/// Entry point of the rendezvous node. The identity is kept separate from the user's own peer identity, so the node keeps
+/// the same PeerId (and therefore the same well-known address) between restarts.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+
+    let cli = Cli::parse();
+    setup_logger()?;
+
+    if let Some(data_dir) = &cli.data_dir {
+        util::set_data_dir(data_dir.clone());
+    }
+
+    let keypair = identity::load_or_generate(&util::data_dir().join("rendezvous"))?;
+
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default
+        )?
+        .with_quic()
+        .with_behaviour(|key| RendezvousBehaviour {
+            rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
+            identify: identify::Behaviour::new(identify::Config::new(
+                "/swapbytes-rendezvous/1.0.0".to_string(),
+                key.public(),
+            )),
+            ping: ping::Behaviour::new(ping::Config::new()),
+        })?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+
+    for address in cli.listen {
+        swarm.listen_on(address)?;
+    }
+
+    let peer_id = *swarm.local_peer_id();
+
+    while let Some(event) = swarm.next().await {
+
+        match event {
+
+            SwarmEvent::NewListenAddr { address, .. } => {
+                log::info!("Listening on {}/p2p/{}", address, peer_id);
+            }
+
+            SwarmEvent::Behaviour(RendezvousBehaviourEvent::Rendezvous(rendezvous::server::Event::PeerRegistered { peer, registration })) => {
+                log::info!("Peer {} registered under {}", peer, registration.namespace);
+            }
+
+            SwarmEvent::Behaviour(RendezvousBehaviourEvent::Rendezvous(rendezvous::server::Event::DiscoverServed { enquirer, registrations })) => {
+                log::info!("Served {} registrations to {}", registrations.len(), enquirer);
+            }
+
+            SwarmEvent::Behaviour(RendezvousBehaviourEvent::Rendezvous(other)) => {
+                log::info!("{:?}", other);
+            }
+
+            other => {
+                log::debug!("{:?}", other);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use swapbytes::{config::Config, daemon::{self, SOCKET_FILE}, events::{self, AppEvent}, network::{client::Client, network}, state::STATE, util};
+use libp2p::{identity::Keypair, Multiaddr};
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::UnixStream, spawn, sync::broadcast::error::RecvError, time::{sleep, timeout, Instant}};
+
+
+/// How long to give a fetch_rooms query to come back before listing the rooms.
+const ROOM_FETCH_DELAY: Duration = Duration::from_secs(2);
+
+
+/// One-shot commands for scripting Swapbytes. By default these are sent to a running peer (see --headless), otherwise
+/// --standalone runs a short-lived peer of its own for the duration of the command.
+#[derive(Parser)]
+#[command(name = "swapbytes-cli", version, about = "Command line client for Swapbytes")]
+struct Cli {
+    #[command(subcommand)]
+    command: CliCommand,
+
+    /// Data directory of the running peer to talk to, as given to it with --data-dir
+    #[arg(long, global = true, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+
+    /// Control socket of the running peer to talk to (defaults to swapbytes.sock in the data directory)
+    #[arg(long, global = true, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Run a short-lived peer for this command instead of talking to a running one
+    #[arg(long, global = true)]
+    standalone: bool,
+
+    /// Nickname of the short-lived peer
+    #[arg(long, global = true, default_value = "swapbytes-cli")]
+    nickname: String,
+
+    /// Path to a JSON config file for the short-lived peer (defaults to config.json in the data directory)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Multiaddr of a peer for the short-lived peer to connect to, on top of those found via mDNS. Can be repeated
+    #[arg(long = "connect", global = true, value_name = "MULTIADDR")]
+    connect: Vec<Multiaddr>,
+
+    /// Seconds the short-lived peer waits to find other peers, and to let the command propagate before exiting
+    #[arg(long, global = true, default_value_t = 5)]
+    wait: u64,
+
+    /// Seconds the short-lived peer waits for a requested file before giving up
+    #[arg(long, global = true, default_value_t = 300)]
+    timeout: u64,
+}
+
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Send a message to a room
+    Send {
+        room: String,
+        message: String,
+    },
+    /// Request a file from a peer (by PeerId or nickname)
+    Request {
+        peer: String,
+        message: String,
+    },
+    /// Respond to a pending file request with a file
+    Respond {
+        request_id: u64,
+        filepath: String,
+        /// Name the file is sent under (defaults to its own name)
+        #[arg(long)]
+        filename: Option<String>,
+    },
+    /// List the pending file requests (to find a request id to respond to)
+    Requests,
+    /// List or create rooms
+    #[command(subcommand)]
+    Rooms(RoomsCommand),
+    /// Rate a peer (by PeerId or nickname) after a swap
+    Rate {
+        peer: String,
+        rating: Rating,
+    },
+    /// List the connected peers
+    Peers,
+    /// List the files being sent and received
+    Transfers,
+    /// Cancel a transfer (by trade id), or clear it from the list if it is already over
+    Cancel {
+        kind: Direction,
+        trade_id: String,
+    },
+    /// Retry a transfer (by trade id) that failed or was cancelled
+    Retry {
+        kind: Direction,
+        trade_id: String,
+    },
+}
+
+
+#[derive(Subcommand)]
+enum RoomsCommand {
+    /// List all known rooms
+    List,
+    /// Create a new room
+    Create {
+        name: String,
+    },
+}
+
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Rating {
+    Good,
+    Bad,
+}
+
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+
+/// Where commands are carried out, either a running peer on the other end of its control socket, or our own short-lived
+/// peer. Both understand the same methods (see daemon.rs).
+enum Backend {
+    Socket(BufReader<UnixStream>),
+    Standalone(Client),
+}
+
+
+impl Backend {
+
+    /// Calls a method, returning its result or the error message.
+    async fn call(&mut self, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+
+        match self {
+
+            Backend::Socket(stream) => {
+                let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+                stream.get_mut().write_all(format!("{}\n", request).as_bytes()).await?;
+
+                let mut line = String::new();
+                if stream.read_line(&mut line).await? == 0 {
+                    return Err("Peer closed the control socket".into());
+                }
+
+                let mut response: Value = serde_json::from_str(&line)?;
+                match response.get("error") {
+                    Some(error) => Err(error["message"].as_str().unwrap_or("Unknown error").into()),
+                    None => Ok(response["result"].take()),
+                }
+            }
+
+            Backend::Standalone(client) => Ok(daemon::dispatch(method, params, client).await?),
+        }
+    }
+
+
+    /// Resolves a peer given by PeerId or nickname to a PeerId.
+    async fn resolve_peer(&mut self, peer: &str) -> Result<String, Box<dyn Error>> {
+
+        if peer.parse::<libp2p::PeerId>().is_ok() {
+            return Ok(peer.to_string());
+        }
+
+        let peers = self.call("peers", Value::Null).await?;
+        let matches: Vec<&str> = peers.as_array().into_iter().flatten()
+            .filter(|p| p["nickname"] == peer)
+            .filter_map(|p| p["peer"].as_str())
+            .collect();
+
+        match matches.as_slice() {
+            [peer_id] => Ok(peer_id.to_string()),
+            [] => Err(format!("No connected peer called {}", peer).into()),
+            _ => Err(format!("More than one peer is called {}, use their PeerId instead", peer).into()),
+        }
+    }
+}
+
+
+/// Starts a short-lived peer and waits (up to the given time) for it to connect to someone.
+async fn start_standalone(cli: &Cli) -> Result<Client, Box<dyn Error>> {
+
+    let path = cli.config.clone().unwrap_or_else(|| Config::default_path(&util::data_dir()));
+    let mut config = Config::load(&path)?;
+    config.bootstrap_peers.extend(cli.connect.iter().map(|addr| addr.to_string()));
+
+    STATE.lock().unwrap().nickname = cli.nickname.clone();
+
+    // A throwaway identity, so we don't clash with a peer already running from this data directory.
+    let (client, event_loop) = network::new(Keypair::generate_ed25519(), &config)?;
+    spawn(event_loop.run(client.clone()));
+
+    let deadline = Instant::now() + Duration::from_secs(cli.wait);
+    while STATE.lock().unwrap().peers.is_empty() && Instant::now() < deadline {
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    if STATE.lock().unwrap().peers.is_empty() {
+        return Err("Couldn't find any peers".into());
+    }
+
+    // Give the new connections a moment to exchange nicknames and room subscriptions.
+    sleep(Duration::from_secs(1)).await;
+    Ok(client)
+}
+
+
+/// Params picking out a transfer, for cancel_transfer and retry_transfer.
+fn transfer_params(kind: &Direction, trade_id: &str) -> Value {
+    let kind = match kind {
+        Direction::Upload => "upload",
+        Direction::Download => "download",
+    };
+    json!({ "trade_id": trade_id, "kind": kind })
+}
+
+
+/// Prints a query result for the user (or a script) to read.
+fn print(result: &Value) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string_pretty(result)?);
+    Ok(())
+}
+
+
+/// Main entry point of the command line client.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+
+    let cli = Cli::parse();
+
+    if let Some(data_dir) = &cli.data_dir {
+        util::set_data_dir(data_dir.clone());
+    }
+
+    let mut backend = if cli.standalone {
+        Backend::Standalone(start_standalone(&cli).await?)
+    } else {
+        let socket = cli.socket.clone().unwrap_or_else(|| util::data_dir().join(SOCKET_FILE));
+        let stream = UnixStream::connect(&socket).await
+            .map_err(|e| format!("Couldn't reach a running peer at {} ({}). Start one with --headless, or use --standalone", socket.display(), e))?;
+        Backend::Socket(BufReader::new(stream))
+    };
+
+    // Subscribed before anything is sent, so a file sent straight back isn't missed.
+    let mut events = events::subscribe();
+
+    match &cli.command {
+
+        CliCommand::Send { room, message } => {
+            backend.call("send_message", json!({ "room": room, "message": message })).await?;
+        }
+
+        CliCommand::Request { peer, message } => {
+            let peer = backend.resolve_peer(peer).await?;
+            backend.call("request_file", json!({ "peer": peer, "message": message })).await?;
+
+            // A running peer receives the file itself. A short-lived one has to stay up until it arrives.
+            if let Backend::Standalone(_) = backend {
+                println!("Waiting for {} to respond...", peer);
+                let received = timeout(Duration::from_secs(cli.timeout), async {
+                    loop {
+                        match events.recv().await {
+                            Ok(AppEvent::FileReceived { filename, sha256, verified, .. }) => return Ok((filename, sha256, verified)),
+                            Err(RecvError::Closed) => return Err("The peer stopped before the file arrived"),
+                            _ => {}
+                        }
+                    }
+                }).await;
+
+                let (filename, sha256, verified) = received.map_err(|_| format!("{} didn't send the file within {} seconds", peer, cli.timeout))??;
+                let check = if verified { "matches" } else { "DOES NOT match" };
+                println!("Received {} (sha256 {}, {} what was offered)", filename, sha256, check);
+            }
+        }
+
+        CliCommand::Respond { request_id, filepath, filename } => {
+            if let Backend::Standalone(_) = backend {
+                return Err("A short-lived peer has no pending requests, respond through the peer that received them".into());
+            }
+            backend.call("respond_file", json!({ "request_id": request_id, "filepath": filepath, "filename": filename })).await?;
+        }
+
+        CliCommand::Requests => {
+            print(&backend.call("requests", Value::Null).await?)?;
+        }
+
+        CliCommand::Rooms(RoomsCommand::List) => {
+            backend.call("fetch_rooms", Value::Null).await?;
+            sleep(ROOM_FETCH_DELAY).await;
+            print(&backend.call("rooms", Value::Null).await?)?;
+        }
+
+        CliCommand::Rooms(RoomsCommand::Create { name }) => {
+            backend.call("create_room", json!({ "name": name })).await?;
+        }
+
+        CliCommand::Rate { peer, rating } => {
+            if let Backend::Standalone(_) = backend {
+                return Err("A short-lived peer has no trades to rate, rate through the peer that received the file".into());
+            }
+            let peer = backend.resolve_peer(peer).await?;
+            let rating = match rating {
+                Rating::Good => 1,
+                Rating::Bad => -1,
+            };
+            backend.call("update_rating", json!({ "peer": peer, "rating": rating })).await?;
+        }
+
+        CliCommand::Peers => {
+            print(&backend.call("peers", Value::Null).await?)?;
+        }
+
+        CliCommand::Transfers => {
+            print(&backend.call("transfers", Value::Null).await?)?;
+        }
+
+        CliCommand::Cancel { kind, trade_id } => {
+            if let Backend::Standalone(_) = backend {
+                return Err("A short-lived peer has no transfers, cancel it through the peer it belongs to".into());
+            }
+            backend.call("cancel_transfer", transfer_params(kind, trade_id)).await?;
+        }
+
+        CliCommand::Retry { kind, trade_id } => {
+            if let Backend::Standalone(_) = backend {
+                return Err("A short-lived peer has no transfers, retry it through the peer it belongs to".into());
+            }
+            backend.call("retry_transfer", transfer_params(kind, trade_id)).await?;
+        }
+    }
+
+    // Let a short-lived peer finish publishing before it disappears.
+    if let Backend::Standalone(_) = backend {
+        sleep(Duration::from_secs(cli.wait)).await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::{error::Error, fs, path::{Path, PathBuf}};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// Name of the config file looked for in the data directory when no --config path is given.
+pub const CONFIG_FILE: &str = "config.json";
+
+
+/// User configurable settings, read from a JSON config file. Any field left out of the file
+/// falls back to its default, and command line flags take priority over the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Multiaddrs to listen on, eg. "/ip4/0.0.0.0/tcp/4001" or "/ip6/::/udp/0/quic-v1".
+    pub listen_addresses: Vec<String>,
+    /// Multiaddrs of peers to dial on startup, eg. "/ip4/10.0.0.5/tcp/4001/p2p/<PeerId>". Used to reach peers that mDNS can't
+    /// find, such as those on another subnet.
+    pub bootstrap_peers: Vec<String>,
+    /// Multiaddr of a rendezvous node, including its PeerId, eg. "/ip4/132.181.1.10/tcp/62649/p2p/<PeerId>".
+    pub rendezvous_point: Option<String>,
+    /// Namespace to register and discover peers under at the rendezvous node, such as the course code.
+    pub rendezvous_namespace: String,
+    /// How many messages of each room (and DM) are kept in the chat history.
+    pub history_limit: usize,
+    /// Directory received files are saved to (defaults to downloads in the data directory).
+    pub downloads_dir: Option<PathBuf>,
+}
+
+
+impl Default for Config {
+
+    /// Listens on TCP and QUIC over both IPv4 and IPv6 with OS assigned ports. TCP is included so that
+    /// networks blocking UDP (and therefore QUIC) can still connect.
+    fn default() -> Self {
+        Self {
+            listen_addresses: vec![
+                "/ip4/0.0.0.0/tcp/0".to_string(),
+                "/ip4/0.0.0.0/udp/0/quic-v1".to_string(),
+                "/ip6/::/tcp/0".to_string(),
+                "/ip6/::/udp/0/quic-v1".to_string(),
+            ],
+            bootstrap_peers: vec![],
+            rendezvous_point: None,
+            rendezvous_namespace: "COSC473".to_string(),
+            history_limit: 1000,
+            downloads_dir: None,
+        }
+    }
+}
+
+
+impl Config {
+
+    /// Reads the config from the given file, or returns the default config if the file does not exist.
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        let config: Config = serde_json::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+
+        Ok(config)
+    }
+
+
+    /// The default location of the config file within the data directory.
+    pub fn default_path(data_dir: &Path) -> PathBuf {
+        data_dir.join(CONFIG_FILE)
+    }
+
+
+    /// Parses the configured listen addresses.
+    pub fn listen_multiaddrs(&self) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+        parse_multiaddrs(&self.listen_addresses, "listen")
+    }
+
+
+    /// Parses the configured bootstrap peer addresses.
+    pub fn bootstrap_multiaddrs(&self) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+        parse_multiaddrs(&self.bootstrap_peers, "bootstrap")
+    }
+
+
+    /// Parses the configured rendezvous node address, which must end in /p2p/<PeerId> so we know who we're registering with.
+    pub fn rendezvous_point(&self) -> Result<Option<(PeerId, Multiaddr)>, Box<dyn Error>> {
+
+        let Some(addr) = &self.rendezvous_point else {
+            return Ok(None);
+        };
+
+        let address = parse_multiaddrs(std::slice::from_ref(addr), "rendezvous")?.remove(0);
+
+        match address.iter().last() {
+            Some(Protocol::P2p(peer_id)) => Ok(Some((peer_id, address))),
+            _ => Err(format!("Rendezvous address {} must end with /p2p/<PeerId>", addr).into()),
+        }
+    }
+}
+
+
+/// Parses a list of multiaddr strings, naming the offending address (and what it was for) on failure.
+fn parse_multiaddrs(addresses: &[String], kind: &str) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    addresses
+        .iter()
+        .map(|addr| addr.parse::<Multiaddr>().map_err(|e| format!("Invalid {} address {}: {}", kind, addr, e).into()))
+        .collect()
+}
@@ -0,0 +1,319 @@
+use std::{error::Error, path::Path};
+use libp2p::{Multiaddr, PeerId};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{UnixListener, UnixStream}, sync::broadcast::error::RecvError};
+
+use crate::{events::{self, AppEvent}, network::client::Client, state::{TransferKind, STATE}, util};
+
+
+/// Name of the control socket (inside the data directory) when no path is given.
+pub const SOCKET_FILE: &str = "swapbytes.sock";
+
+
+/// A request on the control socket. One JSON-RPC 2.0 style object per line, eg.
+/// {"id": 1, "method": "send_message", "params": {"room": "Global", "message": "Hi!"}}
+#[derive(Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+
+/// A response on the control socket, sent as a single line.
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+
+#[derive(Deserialize)]
+struct SendMessageParams { room: String, message: String }
+
+#[derive(Deserialize)]
+struct RequestFileParams { peer: String, message: String }
+
+#[derive(Deserialize)]
+struct RespondFileParams { request_id: u64, filepath: String, filename: Option<String> }
+
+#[derive(Deserialize)]
+struct UpdateRatingParams { peer: String, rating: i32 }
+
+#[derive(Deserialize)]
+struct CreateRoomParams { name: String }
+
+#[derive(Deserialize)]
+struct DialParams { address: String }
+
+#[derive(Deserialize)]
+struct MessagesParams { room: String }
+
+#[derive(Deserialize)]
+struct TransferParams { trade_id: String, kind: TransferKind }
+
+
+/// Serves the control API of a headless peer on a Unix socket. Every network command the UI can perform is available,
+/// along with queries of the current state, and a "subscribe" method that streams incoming messages, requests and files
+/// back as {"jsonrpc": "2.0", "method": "event", "params": {...}} notifications.
+pub async fn serve(socket_path: &Path, client: Client) -> Result<(), Box<dyn Error>> {
+
+    // A socket left behind by a previous run would stop us binding.
+    if socket_path.exists() {
+        std::fs::remove_file(socket_path)?;
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+    log::info!("Control socket listening on {}", socket_path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle_connection(stream, client.clone()));
+    }
+}
+
+
+/// Handles a single control connection until it is closed, answering each request line in turn.
+async fn handle_connection(stream: UnixStream, mut client: Client) {
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut subscription = None;
+
+    loop {
+
+        let output = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim().is_empty() => continue,
+                Ok(Some(line)) => {
+                    let (response, subscribe) = handle_line(&line, &mut client).await;
+                    if subscribe && subscription.is_none() {
+                        subscription = Some(events::subscribe());
+                    }
+                    serde_json::to_string(&response).unwrap()
+                }
+                _ => return,
+            },
+            event = next_event(&mut subscription) => match event {
+                Some(event) => json!({ "jsonrpc": "2.0", "method": "event", "params": event }).to_string(),
+                None => continue,
+            },
+        };
+
+        if writer.write_all(format!("{}\n", output).as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+
+/// Waits for the next event if subscribed, otherwise never resolves.
+async fn next_event(subscription: &mut Option<tokio::sync::broadcast::Receiver<AppEvent>>) -> Option<AppEvent> {
+
+    let Some(receiver) = subscription else {
+        return std::future::pending().await;
+    };
+
+    match receiver.recv().await {
+        Ok(event) => Some(event),
+        Err(RecvError::Lagged(missed)) => {
+            log::info!("Control connection missed {} events", missed);
+            None
+        }
+        Err(RecvError::Closed) => std::future::pending().await,
+    }
+}
+
+
+/// Parses and runs a single request. Also returns whether the connection asked to subscribe to events.
+async fn handle_line(line: &str, client: &mut Client) -> (RpcResponse, bool) {
+
+    let request: RpcRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return (error_response(Value::Null, -32700, format!("Parse error: {}", e)), false),
+    };
+
+    let subscribe = request.method == "subscribe";
+
+    let response = match dispatch(&request.method, request.params, client).await {
+        Ok(result) => RpcResponse { jsonrpc: "2.0", id: request.id, result: Some(result), error: None },
+        Err(e) => error_response(request.id, -32000, e),
+    };
+
+    (response, subscribe)
+}
+
+
+fn error_response(id: Value, code: i32, message: String) -> RpcResponse {
+    RpcResponse { jsonrpc: "2.0", id, result: None, error: Some(RpcError { code, message }) }
+}
+
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, String> {
+    serde_json::from_value(params).map_err(|e| format!("Invalid params: {}", e))
+}
+
+
+fn parse_peer(peer: &str) -> Result<PeerId, String> {
+    peer.parse().map_err(|e| format!("Invalid peer id {}: {}", peer, e))
+}
+
+
+/// Runs a method, mapping onto the same Client commands the UI uses. Also used directly by swapbytes-cli when it runs
+/// its own short-lived peer rather than talking to a running one.
+pub async fn dispatch(method: &str, params: Value, client: &mut Client) -> Result<Value, String> {
+
+    match method {
+
+        // Commands
+        "send_message" => {
+            let SendMessageParams { room, message } = parse_params(params)?;
+            client.send_message(message, room).await;
+            Ok(Value::Null)
+        }
+
+        "request_file" => {
+            let RequestFileParams { peer, message } = parse_params(params)?;
+            client.send_request(message, parse_peer(&peer)?).await;
+            Ok(Value::Null)
+        }
+
+        "respond_file" => {
+            let RespondFileParams { request_id, filepath, filename } = parse_params(params)?;
+
+            let request = {
+                let mut state = STATE.lock().unwrap();
+                let index = state.requests.iter().position(|r| r.id == request_id);
+                index.map(|index| state.requests.remove(index))
+            };
+
+            let request = request.ok_or(format!("No pending request with id {}", request_id))?;
+            client.send_response(filename.unwrap_or_else(|| util::file_name(&filepath)), filepath, request).await;
+            Ok(Value::Null)
+        }
+
+        "update_rating" => {
+            let UpdateRatingParams { peer, rating } = parse_params(params)?;
+            if rating != 1 && rating != -1 {
+                return Err("Rating must be 1 (good) or -1 (bad)".to_string());
+            }
+
+            // Ratings are for a trade, so there must be a file from them we haven't rated yet
+            let peer = parse_peer(&peer)?;
+            let trade = STATE.lock().unwrap().take_trade(&peer).ok_or(format!("No unrated trade with {}", peer))?;
+            client.update_rating(trade, rating).await;
+            Ok(Value::Null)
+        }
+
+        "create_room" => {
+            let CreateRoomParams { name } = parse_params(params)?;
+            util::check_room_name(&name)?;
+            if STATE.lock().unwrap().rooms.contains(&name) {
+                return Err(format!("Room {} already exists", name));
+            }
+            client.create_room(name).await;
+            Ok(Value::Null)
+        }
+
+        "fetch_rooms" => {
+            client.fetch_rooms().await;
+            Ok(Value::Null)
+        }
+
+        "dial" => {
+            let DialParams { address } = parse_params(params)?;
+            let address: Multiaddr = address.parse().map_err(|e| format!("Invalid address {}: {}", address, e))?;
+            client.dial(address).await;
+            Ok(Value::Null)
+        }
+
+        "cancel_transfer" => {
+            let TransferParams { trade_id, kind } = parse_params(params)?;
+            client.cancel_transfer(trade_id, kind).await;
+            Ok(Value::Null)
+        }
+
+        "retry_transfer" => {
+            let TransferParams { trade_id, kind } = parse_params(params)?;
+            client.retry_transfer(trade_id, kind).await;
+            Ok(Value::Null)
+        }
+
+        // Queries
+        "status" => {
+            let state = STATE.lock().unwrap();
+            Ok(json!({ "peer_id": state.peer_id, "nickname": state.nickname, "current_room": state.current_room }))
+        }
+
+        "peers" => {
+            let state = STATE.lock().unwrap();
+            let peers: Vec<Value> = state.peers.iter().map(|peer_id| {
+                let key = peer_id.to_string();
+                json!({
+                    "peer": key,
+                    "nickname": state.nicknames.get(&key),
+                    "client_version": state.client_versions.get(&key),
+                    "rtt_ms": state.peer_health.get(&key).map(|h| h.rtt.as_millis() as u64),
+                    "stale": state.peer_health.get(&key).map(|h| h.is_stale()),
+                })
+            }).collect();
+            Ok(Value::Array(peers))
+        }
+
+        "rooms" => {
+            Ok(json!(STATE.lock().unwrap().rooms))
+        }
+
+        "messages" => {
+            let MessagesParams { room } = parse_params(params)?;
+            Ok(json!(STATE.lock().unwrap().messages.get(&room).cloned().unwrap_or_default()))
+        }
+
+        "requests" => {
+            let state = STATE.lock().unwrap();
+            let requests: Vec<Value> = state.requests.iter().map(|request| json!({
+                "request_id": request.id,
+                "peer": request.peer.to_string(),
+                "nickname": state.nickname_or_id(&request.peer),
+                "message": request.message,
+            })).collect();
+            Ok(Value::Array(requests))
+        }
+
+        "transfers" => {
+            let state = STATE.lock().unwrap();
+            let transfers: Vec<Value> = state.transfers.iter().map(|transfer| json!({
+                "trade_id": transfer.trade_id,
+                "kind": transfer.kind,
+                "peer": transfer.peer.to_string(),
+                "nickname": state.nickname_or_id(&transfer.peer),
+                "filename": transfer.filename,
+                "size": transfer.size,
+                "done": transfer.done,
+                "speed": transfer.speed,
+                "eta_secs": transfer.eta().map(|eta| eta.as_secs()),
+                "state": transfer.state,
+            })).collect();
+            Ok(Value::Array(transfers))
+        }
+
+        // Streams events on this connection from now on
+        "subscribe" => Ok(Value::Bool(true)),
+
+        other => Err(format!("Unknown method {}", other)),
+    }
+}
@@ -0,0 +1,58 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber can fall behind by before it starts missing them.
+const EVENT_BUFFER: usize = 256;
+
+
+/// Things happening on the network that a frontend other than the UI (eg. a script connected to a headless peer) may want
+/// to react to. The UI reads the GlobalState directly instead.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AppEvent {
+    Message {
+        room: String,
+        peer: String,
+        nickname: String,
+        message: String,
+    },
+    PeerJoined {
+        peer: String,
+        nickname: String,
+    },
+    PeerLeft {
+        peer: String,
+        nickname: String,
+    },
+    Request {
+        request_id: u64,
+        peer: String,
+        nickname: String,
+        message: String,
+    },
+    FileReceived {
+        peer: String,
+        filename: String,
+        sha256: String,
+        verified: bool,
+    },
+}
+
+
+// A broadcast channel of AppEvents to be subscribed to from anywhere in the application.
+lazy_static! {
+    pub static ref EVENTS: broadcast::Sender<AppEvent> = broadcast::channel(EVENT_BUFFER).0;
+}
+
+
+/// Publishes an event to all current subscribers. Events are dropped if nobody is listening.
+pub fn emit(event: AppEvent) {
+    let _ = EVENTS.send(event);
+}
+
+
+/// Subscribes to all events published from now on.
+pub fn subscribe() -> broadcast::Receiver<AppEvent> {
+    EVENTS.subscribe()
+}
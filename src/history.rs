@@ -0,0 +1,372 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::{state::{Delivery, Message}, util};
+
+/// Name of the directory (inside the data directory) holding the chat history.
+pub const HISTORY_DIR: &str = "history";
+
+/// How many messages of a room are loaded at a time, on startup and when scrolling back through older messages.
+pub const PAGE_SIZE: usize = 100;
+
+/// How far past the retention limit a room can grow (in lines) before it is trimmed, so the file isn't rewritten on every
+/// message or update.
+const TRIM_SLACK: usize = 100;
+
+
+/// A line of a room's history: either a message, or a later change to one (see History::update).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Message(Message),
+    Update(Update),
+}
+
+
+/// A change to a saved message, folded into it when the history is read.
+#[derive(Serialize, Deserialize)]
+struct Update {
+    /// Id of the message that changed.
+    update: String,
+    delivery: Delivery,
+    rating: Option<i32>,
+}
+
+
+/// The chat history of every room and DM, kept on disk so conversations survive restarts. Each room is an append-only
+/// log of JSON messages (one per line), trimmed to the most recent `retention` messages. Changes to a message (eg. its
+/// delivery being confirmed) are appended too, and folded into the message whenever the room is trimmed.
+pub struct History {
+    dir: PathBuf,
+    retention: usize,
+    /// Lines in each room's file.
+    lengths: HashMap<String, usize>,
+}
+
+
+impl History {
+
+    /// Opens (creating if needed) the history kept in the given data directory.
+    pub fn open(data_dir: &Path, retention: usize) -> io::Result<History> {
+
+        let dir = data_dir.join(HISTORY_DIR);
+        fs::create_dir_all(&dir)?;
+
+        Ok(History { dir, retention, lengths: HashMap::new() })
+    }
+
+
+    /// Reads the most recent page of messages for every room with a history, trimming any room over the retention limit.
+    pub fn load_recent(&mut self) -> io::Result<HashMap<String, Vec<Message>>> {
+
+        let mut recent = HashMap::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "jsonl") {
+                continue;
+            }
+
+            let (mut messages, mut lines) = read_entries(&path)?;
+            let Some(mut room) = messages.first().map(|message| message.room.clone()) else {
+                continue;
+            };
+
+            // DMs used to be kept under "<PeerId>_<PeerId>", before their keys were set apart from room names
+            if let Some(dm_key) = legacy_dm_key(&room) {
+                messages.iter_mut().for_each(|message| message.room = dm_key.clone());
+                self.merge(&dm_key, &messages)?;
+                fs::remove_file(&path)?;
+                (messages, lines) = read_entries(&self.path(&dm_key))?;
+                room = dm_key;
+            }
+
+            if lines > self.retention {
+                messages.drain(..messages.len().saturating_sub(self.retention));
+                write_messages(&self.path(&room), &messages)?;
+                self.lengths.insert(room.clone(), messages.len());
+            } else {
+                self.lengths.insert(room.clone(), lines);
+            }
+
+            messages.drain(..messages.len().saturating_sub(PAGE_SIZE));
+            recent.insert(room, messages);
+        }
+
+        Ok(recent)
+    }
+
+
+    /// Adds a message to the end of its room's history.
+    pub fn append(&mut self, message: &Message) -> io::Result<()> {
+        self.append_line(&message.room, serde_json::to_string(message)?)
+    }
+
+
+    /// Saves the delivery state and rating of a saved message (eg. once its delivery has been confirmed). Only the change is
+    /// appended, so the room's history isn't rewritten for every update.
+    pub fn update(&mut self, message: &Message) -> io::Result<()> {
+        let update = Update { update: message.id.clone(), delivery: message.delivery, rating: message.rating };
+        self.append_line(&message.room, serde_json::to_string(&update)?)
+    }
+
+
+    /// Adds a line to the end of a room's history, trimming the room if it has grown too far past the retention limit.
+    fn append_line(&mut self, room: &str, line: String) -> io::Result<()> {
+
+        let path = self.path(room);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", line)?;
+
+        let length = self.lengths.entry(room.to_string()).or_default();
+        *length += 1;
+
+        if *length > self.retention + TRIM_SLACK {
+            let mut messages = read_messages(&path)?;
+            messages.drain(..messages.len().saturating_sub(self.retention));
+            write_messages(&path, &messages)?;
+            self.lengths.insert(room.to_string(), messages.len());
+        }
+
+        Ok(())
+    }
+
+
+    /// Reads every saved message of a room, oldest first.
+    pub fn read(&self, room: &str) -> io::Result<Vec<Message>> {
+
+        let path = self.path(room);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+
+        read_messages(&path)
+    }
+
+
+    /// Merges messages received from elsewhere (eg. another peer, see history_sync.rs) into a room's history, keeping it
+    /// in clock order and skipping any message already saved.
+    pub fn merge(&mut self, room: &str, messages: &[Message]) -> io::Result<()> {
+
+        let mut saved = self.read(room)?;
+        let known: HashSet<String> = saved.iter().map(|message| message.id.clone()).collect();
+
+        saved.extend(messages.iter().filter(|message| !known.contains(&message.id)).cloned());
+        saved.sort_by_key(Message::order);
+        saved.drain(..saved.len().saturating_sub(self.retention));
+
+        write_messages(&self.path(room), &saved)?;
+        self.lengths.insert(room.to_string(), saved.len());
+        Ok(())
+    }
+
+
+    /// Reads the most recent page of messages of a room that aren't already shown (given by id). Going back from the oldest
+    /// message shown isn't enough, as one caught up on from another peer (see history_sync.rs) can be older than messages
+    /// that haven't been loaded yet.
+    pub fn page(&self, room: &str, shown: &HashSet<&str>) -> io::Result<Vec<Message>> {
+
+        let mut messages = self.read(room)?;
+        messages.retain(|message| !shown.contains(message.id.as_str()));
+        messages.drain(..messages.len().saturating_sub(PAGE_SIZE));
+
+        Ok(messages)
+    }
+
+
+    /// The file a room's history is kept in. Room names are escaped so that any name makes a safe file name.
+    fn path(&self, room: &str) -> PathBuf {
+
+        let name: String = room.chars().map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c.to_string(),
+            other => other.to_string().bytes().map(|byte| format!("%{:02X}", byte)).collect(),
+        }).collect();
+
+        self.dir.join(format!("{}.jsonl", name))
+    }
+}
+
+
+/// The key a DM saved under its old key ("<PeerId>_<PeerId>") is now kept under (see util::format_dm_key). None for any other room.
+fn legacy_dm_key(room: &str) -> Option<String> {
+
+    let (first, second) = room.split_once('_')?;
+    first.parse::<PeerId>().ok()?;
+    second.parse::<PeerId>().ok()?;
+
+    Some(format!("{}{}", util::DM_PREFIX, room))
+}
+
+
+/// Reads every message in a history file, with any updates to them folded in.
+fn read_messages(path: &Path) -> io::Result<Vec<Message>> {
+    Ok(read_entries(path)?.0)
+}
+
+
+/// Reads every message in a history file, with any updates to them folded in, along with how many lines the file has. A
+/// line that can't be read (eg. cut short by a crash) is skipped.
+fn read_entries(path: &Path) -> io::Result<(Vec<Message>, usize)> {
+
+    let contents = fs::read_to_string(path)?;
+    let mut messages: Vec<Message> = vec![];
+    let mut indexes: HashMap<String, usize> = HashMap::new();
+    let mut lines = 0;
+
+    for line in contents.lines() {
+        lines += 1;
+
+        match serde_json::from_str(line) {
+            Ok(Entry::Message(message)) => {
+                indexes.insert(message.id.clone(), messages.len());
+                messages.push(message);
+            }
+            Ok(Entry::Update(update)) => {
+                if let Some(message) = indexes.get(&update.update).map(|&index| &mut messages[index]) {
+                    message.delivery = update.delivery;
+                    message.rating = update.rating;
+                }
+            }
+            Err(e) => log::info!("Skipping unreadable history line in {}: {}", path.display(), e),
+        }
+    }
+
+    Ok((messages, lines))
+}
+
+
+/// Replaces a history file with the given messages.
+fn write_messages(path: &Path, messages: &[Message]) -> io::Result<()> {
+
+    let mut contents = String::new();
+    for message in messages {
+        contents.push_str(&serde_json::to_string(message)?);
+        contents.push('\n');
+    }
+
+    let temporary = path.with_extension("jsonl.tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::state::MessageKind;
+
+    use super::*;
+
+    fn history(retention: usize) -> History {
+        History::open(&util::test_dir(), retention).unwrap()
+    }
+
+
+    /// A chat message in the given room, with the given clock.
+    fn message(room: &str, clock: u64) -> Message {
+        Message { clock, ..Message::system(room, MessageKind::Chat, format!("message {}", clock)) }
+    }
+
+
+    fn clocks(messages: &[Message]) -> Vec<u64> {
+        messages.iter().map(|message| message.clock).collect()
+    }
+
+
+    #[test]
+    fn pages_are_the_most_recent_messages_not_shown() {
+        let mut history = history(1000);
+        let messages: Vec<Message> = (0..250).map(|clock| message("room", clock)).collect();
+        messages.iter().for_each(|message| history.append(message).unwrap());
+
+        let mut shown = HashSet::new();
+        let page = history.page("room", &shown).unwrap();
+        assert_eq!(clocks(&page), (150..250).collect::<Vec<u64>>());
+
+        // Including any left out from between messages already shown
+        shown.extend(page.iter().map(|message| message.id.as_str()));
+        shown.insert(&messages[10].id);
+        assert_eq!(clocks(&history.page("room", &shown).unwrap()), (50..150).collect::<Vec<u64>>());
+
+        shown.extend(messages[50..150].iter().map(|message| message.id.as_str()));
+        assert_eq!(clocks(&history.page("room", &shown).unwrap()), (0..50).filter(|clock| *clock != 10).collect::<Vec<u64>>());
+
+        shown.extend(messages.iter().map(|message| message.id.as_str()));
+        assert!(history.page("room", &shown).unwrap().is_empty());
+        assert!(history.page("other", &HashSet::new()).unwrap().is_empty());
+    }
+
+
+    #[test]
+    fn rooms_are_trimmed_to_the_retention_limit() {
+        let mut history = history(10);
+
+        for clock in 0..(10 + TRIM_SLACK) as u64 {
+            history.append(&message("room", clock)).unwrap();
+        }
+        assert_eq!(history.read("room").unwrap().len(), 10 + TRIM_SLACK);
+
+        history.append(&message("room", (10 + TRIM_SLACK) as u64)).unwrap();
+        assert_eq!(clocks(&history.read("room").unwrap()), (TRIM_SLACK as u64 + 1..=(10 + TRIM_SLACK) as u64).collect::<Vec<u64>>());
+    }
+
+
+    #[test]
+    fn updates_are_folded_into_their_message() {
+        let mut history = history(10);
+        let mut sent = message("room", 1);
+        sent.delivery = Delivery::Pending;
+        history.append(&sent).unwrap();
+        history.append(&message("room", 2)).unwrap();
+
+        sent.delivery = Delivery::Sent;
+        sent.rating = Some(3);
+        history.update(&sent).unwrap();
+
+        let messages = history.read("room").unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], sent);
+
+        // Updates count towards trimming, and are folded in when the room is trimmed
+        for clock in 3..=(10 + TRIM_SLACK) as u64 {
+            history.update(&message("room", clock)).unwrap();
+        }
+        assert_eq!(history.read("room").unwrap(), messages);
+        assert_eq!(fs::read_to_string(history.path("room")).unwrap().lines().count(), 2);
+    }
+
+
+    #[test]
+    fn merged_messages_are_kept_in_order_without_duplicates() {
+        let mut history = history(5);
+        let saved: Vec<Message> = [1, 3, 5].into_iter().map(|clock| message("room", clock)).collect();
+        saved.iter().for_each(|message| history.append(message).unwrap());
+
+        let received = vec![message("room", 4), saved[1].clone(), message("room", 2), message("room", 6)];
+        history.merge("room", &received).unwrap();
+
+        // Only the most recent are kept
+        assert_eq!(clocks(&history.read("room").unwrap()), vec![2, 3, 4, 5, 6]);
+    }
+
+
+    #[test]
+    fn dms_under_their_old_key_are_moved_to_their_new_one() {
+        let mut history = history(10);
+        let key = util::format_dm_key(PeerId::random().to_string(), PeerId::random().to_string());
+        let legacy = key.strip_prefix(util::DM_PREFIX).unwrap().to_string();
+
+        history.append(&message(&legacy, 1)).unwrap();
+        history.append(&message(&key, 2)).unwrap();
+        history.append(&message("cosc473", 3)).unwrap();
+
+        let recent = history.load_recent().unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(clocks(&recent[&key]), vec![1, 2]);
+        assert!(recent[&key].iter().all(|message| message.room == key));
+        assert_eq!(clocks(&recent["cosc473"]), vec![3]);
+        assert!(!history.path(&legacy).exists());
+    }
+}
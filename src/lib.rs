@@ -1,13 +1,25 @@
 pub mod network {
+    #[allow(clippy::module_inception)]
     pub mod network;
     pub mod client;
     pub mod event_loop;
     pub mod command;
+    pub mod identity;
+    pub mod message;
+    pub mod mailbox;
+    pub mod ratings;
+    pub mod transfer;
     pub mod behaviour {
         pub mod mdns;
         pub mod gossipsub;
         pub mod request_response;
         pub mod kademlia;
+        pub mod rendezvous;
+        pub mod identify;
+        pub mod ping;
+        pub mod history_sync;
+        pub mod direct_message;
+        pub mod file_chunk;
     }
 }
 pub mod ui {
@@ -22,4 +34,8 @@ pub mod ui {
     pub mod components;
 }
 pub mod util;
-pub mod state;
\ No newline at end of file
+pub mod config;
+pub mod daemon;
+pub mod state;
+pub mod events;
+pub mod history;
\ No newline at end of file
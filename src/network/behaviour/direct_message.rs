@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use libp2p::{kad::QueryId, request_response::{self, Message, OutboundRequestId}, Swarm};
+use crate::{events::{self, AppEvent}, network::{mailbox::{self, Mailbox, MailboxQuery}, message::ChatMessage, network::{ChatBehaviour, DirectMessageAck}, ratings::RatingCache}, state::{self, Delivery, STATE}, util};
+
+/// Protocol DMs are sent over. Each message goes straight to the other peer over our (encrypted) connection to them, so
+/// unlike a room nobody else can subscribe to it.
+pub const DIRECT_MESSAGE_PROTOCOL: &str = "/swapbytes-dm/1";
+
+
+/// Handles all Direct Message events that come through the network event loop.
+pub async fn handle_event(
+    event: request_response::Event<ChatMessage, DirectMessageAck>,
+    dm_queue: &mut HashMap<OutboundRequestId, ChatMessage>,
+    rating_cache: &mut RatingCache,
+    mailbox_queue: &mut HashMap<QueryId, MailboxQuery>,
+    mailbox: &Mailbox,
+    swarm: &mut Swarm<ChatBehaviour>
+    ) {
+
+    match event {
+
+        request_response::Event::Message { peer, message } => {
+
+            match message {
+
+                // A DM from another peer. Like a room message, it is shown straight away with their rating (see ratings.rs).
+                Message::Request { request: mut chat_message, channel, .. } => {
+
+                    if chat_message.sender != peer.to_string() {
+                        return log::info!("Dropping DM from {} claiming to be from {}", peer, chat_message.sender);
+                    }
+
+                    let id = chat_message.id.clone();
+                    let mut state = STATE.lock().unwrap();
+
+                    // Always shown in our DM with the sender, whatever room they say it's for
+                    chat_message.room = util::format_dm_key(peer.to_string(), state.peer_id.clone());
+                    let nickname = state.nicknames.get(&chat_message.sender).cloned().unwrap_or_else(|| chat_message.nickname.clone());
+
+                    let room = chat_message.room.clone();
+                    let event = AppEvent::Message { room: room.clone(), peer: peer.to_string(), nickname: nickname.clone(), message: chat_message.body.clone() };
+
+                    // A DM sent again (eg. after their ack was lost) is only shown once, but still acknowledged
+                    let known = state.messages.get(&room).is_some_and(|messages| messages.iter().any(|existing| existing.id == id));
+                    if !known {
+                        log::info!("Received DM {} from {}", id, peer);
+
+                        let rating = rating_cache.lookup(swarm, peer, room.clone(), id.clone());
+                        state.push_message(state::Message::chat(chat_message, nickname, Delivery::Received, rating));
+                        state.notifications.insert(room, true);
+                        events::emit(event);
+                    }
+                    drop(state);
+
+                    if swarm.behaviour_mut().direct_message.send_response(channel, DirectMessageAck { id }).is_err() {
+                        log::info!("Failed to acknowledge DM from {}", peer);
+                    }
+                }
+
+                // The other peer has our DM
+                Message::Response { request_id, response } => {
+                    if let Some(message) = dm_queue.remove(&request_id) {
+                        log::info!("DM {} delivered to {}", response.id, peer);
+                        STATE.lock().unwrap().set_delivery(&message.room, &message.id, Delivery::Sent);
+                    }
+                }
+            }
+        }
+
+        // The DM couldn't be delivered (eg. they have gone offline), so it is left in their mailbox for them to collect when
+        // they are back (see mailbox.rs).
+        request_response::Event::OutboundFailure { peer, request_id, error } => {
+            log::info!("Failed to send DM to {}: {}", peer, error);
+            if let Some(message) = dm_queue.remove(&request_id) {
+                mailbox::store(swarm, mailbox, mailbox_queue, peer, &message);
+            }
+        }
+
+        other => {
+            log::info!("{:?}", other);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use libp2p::{identity::Keypair, request_response::{self, Message}, Swarm};
+use crate::network::{network::{ChatBehaviour, ChunkRequest, ChunkResponse}, transfer::Transfers};
+
+/// Protocol offered files are fetched over, a chunk at a time (see transfer.rs).
+pub const FILE_CHUNK_PROTOCOL: &str = "/file-chunk/3";
+
+
+/// Handles all File Chunk events that come through the network event loop.
+pub async fn handle_event(event: request_response::Event<ChunkRequest, ChunkResponse>, transfers: &mut Transfers, keypair: &Keypair, swarm: &mut Swarm<ChatBehaviour>) {
+
+    match event {
+
+        request_response::Event::Message { peer, message } => {
+
+            match message {
+
+                // A peer fetching part of a file we offered them
+                Message::Request { request, channel, .. } => {
+                    let response = transfers.read_chunk(keypair, &peer, &request);
+                    if swarm.behaviour_mut().file_chunk.send_response(channel, response).is_err() {
+                        log::info!("Failed to send chunk at {} to {}", request.offset, peer);
+                    }
+                }
+
+                // Part of a file we are fetching
+                Message::Response { request_id, response } => {
+                    transfers.on_chunk(swarm, &request_id, response);
+                }
+            }
+        }
+
+        request_response::Event::OutboundFailure { peer, request_id, error } => {
+            log::info!("Failed to fetch chunk from {}: {}", peer, error);
+            transfers.on_chunk_failure(swarm, &request_id);
+        }
+
+        other => {
+            log::info!("{:?}", other);
+        }
+    }
+}
@@ -1,39 +1,83 @@
-use std::collections::HashMap;
+use libp2p::{gossipsub, Swarm};
 
-use libp2p::{gossipsub, kad::{self, QueryId}, Swarm};
-
-use crate::{network::network::ChatBehaviour, state::STATE};
+use crate::{events::{self, AppEvent}, network::{behaviour::history_sync, message::ChatMessage, network::ChatBehaviour, ratings::{self, RatingCache, RATINGS_TOPIC}}, state::{Delivery, Message, STATE}, util};
 
 /// Handles all Gossipsub events that come through the network event loop.
-pub async fn handle_event(event: libp2p::gossipsub::Event, rating_fetch_queue: &mut HashMap<QueryId, (String, String, String)>, swarm: &mut Swarm<ChatBehaviour>) {
+pub async fn handle_event(event: libp2p::gossipsub::Event, rating_cache: &mut RatingCache, swarm: &mut Swarm<ChatBehaviour>) {
 
     match event {
         
-        // In the event we recieve a message, we add the message to a queue while we wait for the retreival of the rating for the
-        // user who sent the message. This message will be displayed on screen after this fetch has complete (see kademlia.rs).
+        // In the event we recieve a message, it is shown in its room straight away (in clock order, see state.rs) along with
+        // the rating of the user who sent it. If we don't have their rating yet, it is added once fetched (see ratings.rs).
         gossipsub::Event::Message {
-            propagation_source: peer_id,
+            propagation_source: _,
             message_id: _id,
             message,
         } => {
+                let topic = message.topic.to_string();
+
+                // Not a room, but someone letting us know a rating has changed
+                if topic == RATINGS_TOPIC {
+                    return ratings::on_announcement(rating_cache, &message.data);
+                }
+
+                // DMs are never published, so this can only be someone trying to slip a message into one
+                if util::is_dm(&topic) {
+                    return log::info!("Dropping message published on DM {}", topic);
+                }
+
+                // The source is the (signature verified) author, which may not be the peer who forwarded it to us.
+                let Some(source) = message.source else {
+                    return log::info!("Dropping unsigned message on topic {}", topic);
+                };
+
+                let mut chat_message = match ChatMessage::decode(&message.data) {
+                    Ok(chat_message) => chat_message,
+                    Err(e) => return log::info!("Dropping unreadable message from {} on topic {}: {}", source, topic, e),
+                };
+
+                if chat_message.sender != source.to_string() {
+                    return log::info!("Dropping message from {} claiming to be from {}", source, chat_message.sender);
+                }
+
+                // The topic decides which room it is shown in.
+                if chat_message.room != topic {
+                    log::info!("Message {} for room {} was published on topic {}", chat_message.id, chat_message.room, topic);
+                    chat_message.room = topic.clone();
+                }
+
                 let mut state = STATE.lock().unwrap();
 
-                // Message display information
-                let topic = message.topic.to_string();
-                let data = String::from_utf8_lossy(&message.data).to_string();
-                let nickname = state.nicknames.get(&peer_id.to_string()).expect("User not found").clone();      
+                // Prefer the nickname we already know them by. A message can arrive before that is known, in which case
+                // the one they sent with the message is used.
+                let nickname = match state.nicknames.get(&chat_message.sender) {
+                    Some(nickname) => nickname.clone(),
+                    None if !chat_message.nickname.is_empty() => chat_message.nickname.clone(),
+                    None => state.nickname_or_id(&source),
+                };
 
-                // Notify we have received a message for this room
-                state.notifications.insert(topic.clone(), true);       
+                log::info!("Received message {}: {} on Topic: {}", chat_message.id, chat_message.body, topic);
+                let event = AppEvent::Message { room: topic.clone(), peer: chat_message.sender.clone(), nickname: nickname.clone(), message: chat_message.body.clone() };
+
+                // Skip messages we already have (eg. from catching up on the room, see history_sync.rs)
+                if state.messages.get(&topic).is_some_and(|messages| messages.iter().any(|existing| existing.id == chat_message.id)) {
+                    return;
+                }
 
-                // Fetch the users rating from the DHT (appending the message information to a queue)
-                let key_string = "rating_".to_string() + &peer_id.to_string();
-                let key = kad::RecordKey::new(&key_string);
-                let query_id = swarm.behaviour_mut().kademlia.get_record(key);
-                rating_fetch_queue.insert(query_id, (data, nickname, topic.clone()));
+                let rating = rating_cache.lookup(swarm, source, topic.clone(), chat_message.id.clone());
+                state.push_message(Message::chat(chat_message, nickname, Delivery::Received, rating));
 
-                log::info!("Received message: {} on Topic: {}", String::from_utf8_lossy(&message.data), topic);
-            }  
+                // Notify we have received a message for this room
+                state.notifications.insert(topic.clone(), true);
+                events::emit(event);
+            }
+
+        // A peer has joined one of the rooms. If it's one of ours, they may have messages we missed (see history_sync.rs).
+        gossipsub::Event::Subscribed { peer_id, topic } => {
+            if topic.as_str() != RATINGS_TOPIC {
+                history_sync::request_history(swarm, peer_id, topic);
+            }
+        }
 
         other => {
             log::info!("{:?}", other);
@@ -0,0 +1,111 @@
+use libp2p::{gossipsub::TopicHash, request_response::{self, Message}, PeerId, Swarm};
+use crate::{history::PAGE_SIZE, network::{message::ChatMessage, network::{ChatBehaviour, HistoryRequest, HistoryResponse}, ratings::RatingCache}, state::{self, Delivery, STATE}, util};
+
+/// Protocol for catching up on the messages of a room sent while we weren't subscribed to it.
+pub const HISTORY_SYNC_PROTOCOL: &str = "/swapbytes-history/1";
+
+
+/// Asks a peer who has just been seen in one of our rooms for the messages we are missing from it. Gossipsub only delivers
+/// messages to peers subscribed at the time, so without this a peer joining late (or coming back online) sees nothing earlier.
+pub fn request_history(swarm: &mut Swarm<ChatBehaviour>, peer: PeerId, topic: TopicHash) {
+
+    // Only rooms we are in
+    if !swarm.behaviour().gossipsub.topics().any(|subscribed| *subscribed == topic) {
+        return;
+    }
+
+    let room = topic.to_string();
+    let (since, after) = match STATE.lock().unwrap().newest_message(&room) {
+        Some(newest) => (newest.timestamp, Some(newest.id.clone())),
+        None => (0, None),
+    };
+
+    log::info!("Asking {} for the messages of {} since {}", peer, room, since);
+    swarm.behaviour_mut().history_sync.send_request(&peer, HistoryRequest { room, since, after });
+}
+
+
+/// Handles all History Sync events that come through the network event loop.
+pub async fn handle_event(event: request_response::Event<HistoryRequest, HistoryResponse>, rating_cache: &mut RatingCache, swarm: &mut Swarm<ChatBehaviour>) {
+
+    match event {
+
+        request_response::Event::Message { peer, message } => {
+
+            match message {
+
+                // Another peer is catching up on a room. Send them the (most recent) messages they are missing, as long as
+                // we are in the room ourselves. DMs are private, so they are never sent.
+                Message::Request { request, channel, .. } => {
+
+                    let in_room = !util::is_dm(&request.room) && swarm.behaviour().gossipsub.topics().any(|topic| topic.as_str() == request.room);
+
+                    let messages = if in_room {
+                        let mut messages = STATE.lock().unwrap().messages_since(&request.room, request.since, request.after.as_deref());
+                        messages.drain(..messages.len().saturating_sub(PAGE_SIZE));
+                        messages.iter().filter_map(state::Message::to_chat_message).collect()
+                    } else {
+                        vec![]
+                    };
+
+                    log::info!("Sending {} messages of {} to {}", messages.len(), request.room, peer);
+                    if swarm.behaviour_mut().history_sync.send_response(channel, HistoryResponse { messages }).is_err() {
+                        log::info!("Failed to send history to {}", peer);
+                    }
+                }
+
+                // Merge in the messages we were missing. The same messages may come back from several members of the room,
+                // so anything we already have is skipped. Messages for rooms we aren't in are ignored, and so is any message
+                // its author didn't sign, as the peer passing it on could have written it themselves.
+                Message::Response { response, .. } => {
+
+                    // We never send more than a page (see above), so nor should anyone else
+                    if response.messages.len() > PAGE_SIZE {
+                        return log::info!("Ignoring {} missed messages from {}, more than the {} allowed", response.messages.len(), peer, PAGE_SIZE);
+                    }
+
+                    let messages: Vec<ChatMessage> = response.messages.into_iter()
+                        .filter(|message| !util::is_dm(&message.room) && swarm.behaviour().gossipsub.topics().any(|topic| topic.as_str() == message.room))
+                        .filter(|message| message.verify().map_err(|e| log::info!("Dropping missed message from {}: {}", peer, e)).is_ok())
+                        .collect();
+
+                    let mut rooms: Vec<String> = messages.iter().map(|message| message.room.clone()).collect();
+                    rooms.sort();
+                    rooms.dedup();
+
+                    let own_peer_id = *swarm.local_peer_id();
+                    let mut state = STATE.lock().unwrap();
+
+                    for room in rooms {
+
+                        let mut merging = vec![];
+                        for message in messages.iter().filter(|message| message.room == room) {
+
+                            let Ok(sender) = message.sender.parse::<PeerId>() else {
+                                continue;
+                            };
+
+                            // Only the nickname we know them by can be trusted, as the one in the message isn't signed
+                            let nickname = state.nickname_or_id(&sender);
+
+                            // Shown with the author's rating like any other message, once it is known (see ratings.rs)
+                            let rating = match sender == own_peer_id {
+                                true => None,
+                                false => rating_cache.lookup(swarm, sender, room.clone(), message.id.clone()),
+                            };
+
+                            merging.push(state::Message::chat(message.clone(), nickname, Delivery::Received, rating));
+                        }
+
+                        let merged = state.merge_messages(&room, merging);
+                        log::info!("Merged {} missed messages of {} from {}", merged, room, peer);
+                    }
+                }
+            }
+        }
+
+        other => {
+            log::info!("{:?}", other);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use libp2p::{identify, Swarm};
+use crate::{events::{self, AppEvent}, network::network::ChatBehaviour, state::{MessageKind, STATE}, util};
+
+/// Identify protocol version shared by all Swapbytes peers.
+pub const PROTOCOL_VERSION: &str = "/swapbytes/1.0.0";
+
+/// Prefix of the agent version advertised by Swapbytes peers, eg. "swapbytes/0.1.0/Geordie".
+const AGENT_PREFIX: &str = "swapbytes";
+
+
+/// Formats our agent version, carrying the client version and our nickname so peers know who we are as soon as we connect.
+pub fn agent_version(nickname: &str) -> String {
+    format!("{}/{}/{}", AGENT_PREFIX, env!("CARGO_PKG_VERSION"), nickname)
+}
+
+
+/// Extracts the (client version, nickname) from another peers agent version. Returns None for non-Swapbytes peers
+/// (eg. a rendezvous node).
+pub fn parse_agent_version(agent_version: &str) -> Option<(String, String)> {
+
+    let mut parts = agent_version.splitn(3, '/');
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(AGENT_PREFIX), Some(version), Some(nickname)) if !nickname.is_empty() => Some((version.to_string(), nickname.to_string())),
+        _ => None,
+    }
+}
+
+
+/// Handles all Identify events that come through the network event loop.
+pub async fn handle_event(event: identify::Event, swarm: &mut Swarm<ChatBehaviour>) {
+
+    match event {
+
+        // Sent by every peer when a connection is made. Gives us their nickname straight away, so they show up in the UI without
+        // waiting on the DHT (which is still used as a fallback, see kademlia.rs).
+        identify::Event::Received { peer_id, info } => {
+
+            // Their listen addresses are dialable, unlike the address of an inbound connection.
+            for address in info.listen_addrs {
+                swarm.behaviour_mut().kademlia.add_address(&peer_id, address);
+            }
+
+            let Some((version, nickname)) = parse_agent_version(&info.agent_version) else {
+                log::info!("Peer {} is not a Swapbytes client ({})", peer_id, info.agent_version);
+                return;
+            };
+
+            if info.protocol_version != PROTOCOL_VERSION {
+                log::info!("Peer {} uses protocol {} (we use {})", peer_id, info.protocol_version, PROTOCOL_VERSION);
+            }
+
+            log::info!("Identified {} as {} (client {})", peer_id, nickname, version);
+
+            let mut state = STATE.lock().unwrap();
+            let dm_key = util::format_dm_key(peer_id.to_string(), state.peer_id.clone());
+
+            let is_new = state.nicknames.insert(peer_id.to_string(), nickname.clone()).is_none();
+            state.client_versions.insert(peer_id.to_string(), version);
+
+            if is_new && state.peers.contains(&peer_id) {
+                state.announce(MessageKind::Join, format!("👋 {} joined", nickname));
+                events::emit(AppEvent::PeerJoined { peer: peer_id.to_string(), nickname: nickname.clone() });
+            }
+
+            state.open_room(&dm_key, format!("😀 Chatting with {}", nickname));
+        }
+
+        other => {
+            log::info!("{:?}", other);
+        }
+    }
+}
@@ -1,15 +1,35 @@
-use std::collections::HashMap;
-use libp2p::{gossipsub, kad::{self, QueryId}, PeerId, Swarm};
-use serde::Deserialize;
-use crate::{network::network::ChatBehaviour, state::STATE};
+use std::{collections::{hash_map::Entry, HashMap}, error::Error};
+use libp2p::{gossipsub, kad::{self, store::RecordStore, QueryId}, PeerId, Swarm};
+use crate::{events::{self, AppEvent}, network::{mailbox::{self, Mailbox, MailboxQuery, MailboxRecord}, network::ChatBehaviour, ratings::{self, RatingCache, SignedAttestation}}, state::{MessageKind, STATE}, util};
 
 /// Defines the different types of values stored in the Kademlia DHT.
-#[derive(Deserialize)]
-#[serde(untagged)]
 enum Value {
     Nickname(String),
-    Rating(i32),
-    Rooms(Vec<String>)
+    Rating(PeerId, Vec<SignedAttestation>),
+    Rooms(Vec<String>),
+    Mailbox(PeerId, MailboxRecord),
+}
+
+
+impl Value {
+
+    /// Decodes a record. Which type of value it holds is decided by its key, eg. "rating_<PeerId>" holds a Rating.
+    fn decode(key: &kad::RecordKey, value: &[u8]) -> Result<Value, Box<dyn Error>> {
+
+        let key = std::str::from_utf8(key.as_ref())?;
+
+        if key.starts_with("nickname_") {
+            Ok(Value::Nickname(serde_cbor::from_slice(value)?))
+        } else if let Some(peer_id) = key.strip_prefix("rating_") {
+            Ok(Value::Rating(peer_id.parse()?, serde_cbor::from_slice(value)?))
+        } else if key == "rooms" {
+            Ok(Value::Rooms(serde_cbor::from_slice(value)?))
+        } else if let Some(peer_id) = key.strip_prefix("mailbox_") {
+            Ok(Value::Mailbox(peer_id.parse()?, serde_cbor::from_slice(value)?))
+        } else {
+            Err(format!("Unknown record {}", key).into())
+        }
+    }
 }
 
 
@@ -17,8 +37,10 @@ enum Value {
 pub async fn handle_event(
     event: libp2p::kad::Event,
     nickname_fetch_queue: &mut HashMap<QueryId, (PeerId, String)>,
-    rating_fetch_queue: &mut HashMap<QueryId, (String, String, String)>,
-    rating_update_queue: &mut HashMap<QueryId, (PeerId, i32)>,
+    rating_cache: &mut RatingCache,
+    rating_update_queue: &mut HashMap<QueryId, (PeerId, SignedAttestation)>,
+    mailbox_queue: &mut HashMap<QueryId, MailboxQuery>,
+    mailbox: &Mailbox,
     swarm: &mut Swarm<ChatBehaviour>
     ) {
 
@@ -36,7 +58,7 @@ pub async fn handle_event(
                     })
                 )) => {
 
-                    match serde_cbor::from_slice::<Value>(&value) {
+                    match Value::decode(&key, &value) {
                         
                         // If the returned value was of type Nickname, this means another users nickname has been fetched from the DHT (this will have
                         // been called on connection with another peer). After the fetch, we store the nickname for the peer in our local storage and
@@ -46,61 +68,40 @@ pub async fn handle_event(
 
                             if nickname_fetch_queue.contains_key(&id) {
 
-                                // Add nickname to local storage
+                                // Add nickname to local storage. This is a fallback for when identify hasn't given us the nickname (see identify.rs).
                                 let (peer_id, dm_key) = nickname_fetch_queue.remove(&id).unwrap();
                                 let mut state = STATE.lock().unwrap();
-                                state.nicknames.insert(peer_id.to_string(), nickname);
+                                if let Entry::Vacant(entry) = state.nicknames.entry(peer_id.to_string()) {
+                                    entry.insert(nickname.clone());
+                                    if state.peers.contains(&peer_id) {
+                                        state.announce(MessageKind::Join, format!("👋 {} joined", nickname));
+                                        events::emit(AppEvent::PeerJoined { peer: peer_id.to_string(), nickname: nickname.clone() });
+                                    }
+                                }
 
                                 // Add personalised message to the user's direct message.
-                                let nickname = state.nicknames.get(&peer_id.to_string()).unwrap().clone();      
-                                state.messages.insert(dm_key.clone(), vec![format!("😀 Chatting with {}", nickname.clone())]);
+                                state.open_room(&dm_key, format!("😀 Chatting with {}", nickname));
                             }                           
 
                         }
 
-                        // If the returned value was of type Rating, this means another users rating has been fetched from the DHT. This is used in two senarios,
+                        // If the returned value was of type Rating, this means another users ratings have been fetched from the DHT. Each one is signed by the
+                        // peer who gave it, and only those that verify count towards their rating (see ratings.rs). This is used in two senarios,
                         // 1 - When we receive a message from another user and want to display their most up to date rating, and 2 - when we want to update the peers
                         // rating after recieving a file from them.
-                        Ok(Value::Rating(rating)) => {
-
-                            // In the event we have recieved a message and simply want to fetch the users rating, the message will be in the rating_fetch_queue (See gossibsub.rs).
-                            // The queue contains the message information with a Kademlia QueryID which is matched to the QueryID of this rating fetch. The newly created message (with the rating) 
-                            // is appended to the messages list for the room to be displayed.
-                            if rating_fetch_queue.contains_key(&id) {
-
-                                let (message, nickname, topic) = rating_fetch_queue.remove(&id).unwrap();
-                                
-                                let msg = if rating > 0 {
-                                    format!("{} {}: {}", "😇", nickname, message)
-                                } else if rating < 0 {
-                                    format!("{} {}: {}", "👿", nickname, message)
-                                } else {
-                                    format!("{}: {}", nickname, message)
-                                };
-
-                                let mut state = STATE.lock().unwrap();
-                                state.messages.get_mut(&topic.to_string()).expect("").push(msg);
-                                
-                            // In the event we have just given a rating to another peer after a trade, the peer_id and the rating will be in the rating_update_queue (see rating.rs).
-                            // The queue contains the rating information with a Kademlia QueryID which is matched to the QueryID of this rating fetch. The fetched rating is updated (either +1 or -1)
-                            // and pushed back to the DHT.
-                            } else if rating_update_queue.contains_key(&id) {
-                                
-                                let (peer_id, adjustment) = rating_update_queue.remove(&id).unwrap();
-
-                                let key = "rating_".to_string() + &peer_id.to_string();
-                                let new_rating = rating + adjustment;
-                                let rating_bytes = serde_cbor::to_vec(&new_rating).unwrap();
-
-                                let record = kad::Record {
-                                    key: kad::RecordKey::new(&key),
-                                    value: rating_bytes,
-                                    publisher: None,
-                                    expires: None,
-                                };
-
-                                swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One).expect("Failed to store record");
-
+                        Ok(Value::Rating(peer_id, attestations)) => {
+
+                            // In the event we have just given a rating to another peer after a trade, the peer_id and our signed rating will be in the rating_update_queue (see rating.rs).
+                            // The queue contains the rating information with a Kademlia QueryID which is matched to the QueryID of this rating fetch. Our rating is added to
+                            // the ones they already have and pushed back to the DHT.
+                            if let Some((peer_id, attestation)) = rating_update_queue.remove(&id) {
+                                ratings::finish_rating(swarm, rating_cache, peer_id, attestations, attestation);
+
+                            // In the event we have recieved a message and simply want to fetch the users rating, the fetch will be one of the rating_caches (See ratings.rs).
+                            // The rating is cached, and added to any messages from the user that were shown before it was known.
+                            } else {
+                                let rating = ratings::score(&ratings::verify_all(&peer_id, attestations));
+                                rating_cache.on_rating(&id, Some(rating));
                             }
                         }
 
@@ -109,6 +110,9 @@ pub async fn handle_event(
                         // to note that only "created" rooms are stored in the DHT, not the default rooms.
                         Ok(Value::Rooms(mut rooms)) => {
 
+                            // Anyone can add a room, so any that could be taken for a DM are left out (see util::check_room_name)
+                            rooms.retain(|room| util::check_room_name(room).map_err(|e| log::info!("Ignoring room: {}", e)).is_ok());
+
                             // Updates the rooms list with created AND default rooms
                             let mut state = STATE.lock().unwrap();
                             let mut default_rooms = vec!["Global".to_string(), "COSC473".to_string(), "COSC478".to_string(), "SENG406".to_string(), "SENG402".to_string()];
@@ -120,16 +124,55 @@ pub async fn handle_event(
                             for room in rooms {
                     
                                 let topic = gossipsub::IdentTopic::new(room.to_string());
-                                swarm.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
-
-                                if !state.messages.contains_key(&room) {
-                                    state.messages.entry(room.clone()).or_insert(vec![format!("✨ Welcome to the {} chat!", &room)]);
+                                if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                                    log::info!("Failed to join room {}: {:?}", room, e);
+                                    continue;
                                 }
+
+                                state.open_room(&room, format!("✨ Welcome to the {} chat!", &room));
                             }   
                         }
 
+                        // If the returned value was a Mailbox, we are either reading our own for DMs sent while we were offline, or
+                        // adding a DM to someone else's (see mailbox.rs).
+                        Ok(Value::Mailbox(_, record)) => {
+                            if let Some(query) = mailbox_queue.remove(&id) {
+                                mailbox::on_record(swarm, mailbox, mailbox_queue, query, key, Some(record));
+                            }
+                        }
+
+                        // A mailbox that can't be read (eg. stored by an older client) is treated as empty, so it is replaced
                         Err(e) => {
                             log::info!("Error deserializing {e:?}");
+                            rating_cache.on_rating(&id, None);
+                            if let Some(query) = mailbox_queue.remove(&id) {
+                                mailbox::on_record(swarm, mailbox, mailbox_queue, query, key, None);
+                            }
+                        }
+                    }
+                }
+
+                // A mailbox that doesn't exist yet is empty.
+                kad::QueryResult::GetRecord(Err(error)) => {
+                    log::info!("Failed to get record: {:?}", error);
+
+                    // Any messages waiting on it are already shown, they just go without a rating. A peer nobody has rated yet
+                    // doesn't have a rating record, so starts at 0.
+                    let not_found = matches!(error, kad::GetRecordError::NotFound { .. });
+                    rating_cache.on_rating(&id, not_found.then_some(0));
+
+                    // Nobody has rated them yet, so ours is the first
+                    if let Some((peer_id, attestation)) = rating_update_queue.remove(&id) {
+                        match not_found {
+                            true => ratings::finish_rating(swarm, rating_cache, peer_id, vec![], attestation),
+                            false => log::info!("Failed to rate {}: couldn't read their current ratings", peer_id),
+                        }
+                    }
+
+                    if let Some(query) = mailbox_queue.remove(&id) {
+                        match error {
+                            kad::GetRecordError::NotFound { key, .. } => mailbox::on_record(swarm, mailbox, mailbox_queue, query, key, None),
+                            _ => mailbox::on_failure(query),
                         }
                     }
                 }
@@ -139,6 +182,29 @@ pub async fn handle_event(
                 }
             }
         }
+
+        // Records other peers ask us to store are checked before they are kept (see network.rs). Rating records and mailboxes
+        // are merged with the ones we already have, so nobody can take ratings or DMs out of them (see ratings.rs and mailbox.rs).
+        kad::Event::InboundRequest { request: kad::InboundRequest::PutRecord { source, record: Some(record), .. } } => {
+
+            let record = match Value::decode(&record.key, &record.value) {
+                Ok(Value::Rating(peer_id, attestations)) => ratings::merge_record(swarm.behaviour_mut().kademlia.store_mut(), &peer_id, record, attestations),
+                Ok(Value::Mailbox(peer_id, mailbox)) => mailbox::merge_record(swarm.behaviour_mut().kademlia.store_mut(), &peer_id, record, mailbox),
+                Ok(_) => Ok(record),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = record.and_then(|record| Ok(swarm.behaviour_mut().kademlia.store_mut().put(record)?)) {
+                log::info!("Not storing record from {}: {}", source, e);
+            }
+        }
+
+        kad::Event::InboundRequest { request: kad::InboundRequest::AddProvider { record: Some(record) } } => {
+            if let Err(e) = swarm.behaviour_mut().kademlia.store_mut().add_provider(record) {
+                log::info!("Not storing provider record: {}", e);
+            }
+        }
+
         other => {
             log::info!("{:?}", other);
         }
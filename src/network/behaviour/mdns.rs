@@ -1,44 +1,38 @@
-use std::collections::HashMap;
-
-use libp2p::kad::QueryId;
-use libp2p::{gossipsub, kad, mdns, PeerId, Swarm};
-use crate::state::STATE;
+use libp2p::{mdns, swarm::dial_opts::{DialOpts, PeerCondition}, Swarm};
+use crate::network::event_loop::remove_peer;
 use crate::network::network::ChatBehaviour;
-use crate::util;
 
 // Handles all MDNS events that come through the network event loop.
-pub async fn handle_event(event: libp2p::mdns::Event, swarm: &mut Swarm<ChatBehaviour>, nickname_fetch_queue: &mut HashMap<QueryId, (PeerId, String)>) {
+pub async fn handle_event(event: libp2p::mdns::Event, swarm: &mut Swarm<ChatBehaviour>) {
 
     match event {
 
-        // Handles the connection with a new peer.
+        // Handles the discovery of a peer on the local network. We dial them, and they are added as a peer once the
+        // connection is established (see event_loop.rs).
         mdns::Event::Discovered(list) => {
 
             for (peer_id, addr) in list {
-                log::info!("Connected with person with id: {peer_id}");
-    
-                // Add the peer to our network
-                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
-    
-                // Update local peers
-                let mut state = STATE.lock().unwrap();
-                state.peers.push(peer_id);
-                
-                // Subscribe to the DM for this user and add it to our local storage
-                let own_peer_id = state.peer_id.clone();
-                let dm_key = util::format_dm_key(peer_id.to_string(), own_peer_id);
-                let topic = gossipsub::IdentTopic::new(dm_key.clone());
-                swarm.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
-                
-                // Fetch the users nickname from the DHT
-                let key_string = "nickname_".to_string() + &peer_id.to_string();
-                let key = kad::RecordKey::new(&key_string);
-                let query_id = swarm.behaviour_mut().kademlia.get_record(key);
-                nickname_fetch_queue.insert(query_id, (peer_id, dm_key));
+                swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+
+                let opts = DialOpts::peer_id(peer_id).condition(PeerCondition::DisconnectedAndNotDialing).addresses(vec![addr]).build();
+                if let Err(e) = swarm.dial(opts) {
+                    log::info!("Failed to dial {}: {}", peer_id, e);
+                }
             }
         }
 
-        _ => {}
+        // Handles a peer on the local network no longer answering mDNS queries. Their address is stale, and if we no longer
+        // have a connection to them they have gone.
+        mdns::Event::Expired(list) => {
+
+            for (peer_id, addr) in list {
+                log::info!("mDNS record for {} at {} expired", peer_id, addr);
+                swarm.behaviour_mut().kademlia.remove_address(&peer_id, &addr);
+
+                if !swarm.is_connected(&peer_id) {
+                    remove_peer(peer_id, swarm);
+                }
+            }
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+use std::time::{Duration, Instant};
+use libp2p::{ping, Swarm};
+use crate::{network::network::ChatBehaviour, state::{PeerHealth, STATE}};
+
+/// How often each connected peer is pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Consecutive failed pings after which the connection is considered dead and closed.
+const MAX_PING_FAILURES: u32 = 3;
+
+
+/// Handles all Ping events that come through the network event loop.
+pub async fn handle_event(event: ping::Event, swarm: &mut Swarm<ChatBehaviour>) {
+
+    let ping::Event { peer, connection, result } = event;
+
+    match result {
+
+        // Record the round trip time so it can be shown next to the peers nickname.
+        Ok(rtt) => {
+            let mut state = STATE.lock().unwrap();
+            state.peer_health.insert(peer.to_string(), PeerHealth { rtt, last_seen: Instant::now(), failures: 0 });
+        }
+
+        // Peers that don't speak ping (eg. a rendezvous node without it) aren't unhealthy, just quiet.
+        Err(ping::Failure::Unsupported) => {}
+
+        // A missed ping marks the peer as stale. If they keep missing them, the connection is closed, and once their
+        // last connection has gone they are removed as a peer (see event_loop.rs).
+        Err(failure) => {
+            log::info!("Ping to {} failed: {}", peer, failure);
+
+            let failures = {
+                let mut state = STATE.lock().unwrap();
+                let health = state.peer_health.entry(peer.to_string()).or_insert(PeerHealth {
+                    rtt: Duration::ZERO,
+                    last_seen: Instant::now(),
+                    failures: 0,
+                });
+                health.failures += 1;
+                health.failures
+            };
+
+            if failures >= MAX_PING_FAILURES {
+                log::info!("Closing connection to {} after {} failed pings", peer, failures);
+                swarm.close_connection(connection);
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use libp2p::{rendezvous::{self, Cookie, Namespace}, swarm::dial_opts::DialOpts, Multiaddr, PeerId, Swarm};
+use crate::network::network::ChatBehaviour;
+
+/// A well-known rendezvous node (eg. run by a lecturer) that peers register with under a namespace such as the course code,
+/// and discover every other peer registered under it.
+pub struct RendezvousPoint {
+    pub peer_id: PeerId,
+    pub address: Multiaddr,
+    pub namespace: Namespace,
+    pub cookie: Option<Cookie>,
+}
+
+
+impl RendezvousPoint {
+
+    /// Registers ourselves under the namespace and asks for any registrations we haven't seen yet.
+    /// Called when we connect to the rendezvous node, and periodically after that to pick up new peers.
+    pub fn register_and_discover(&self, swarm: &mut Swarm<ChatBehaviour>) {
+
+        if let Err(e) = swarm.behaviour_mut().rendezvous.register(self.namespace.clone(), self.peer_id, None) {
+            log::info!("Failed to register with rendezvous node {}: {}", self.peer_id, e);
+        }
+
+        swarm.behaviour_mut().rendezvous.discover(Some(self.namespace.clone()), self.cookie.clone(), None, self.peer_id);
+    }
+}
+
+
+/// Handles all Rendezvous events that come through the network event loop.
+pub async fn handle_event(event: rendezvous::client::Event, swarm: &mut Swarm<ChatBehaviour>, rendezvous_point: &mut Option<RendezvousPoint>) {
+
+    match event {
+
+        // Other peers registered under our namespace. We dial each of them, and they will be added as peers once the
+        // connection is established (see event_loop.rs).
+        rendezvous::client::Event::Discovered { registrations, cookie, .. } => {
+
+            if let Some(point) = rendezvous_point {
+                point.cookie = Some(cookie);
+            }
+
+            let local_peer_id = *swarm.local_peer_id();
+
+            for registration in registrations {
+
+                let peer_id = registration.record.peer_id();
+                let addresses = registration.record.addresses().to_vec();
+
+                if peer_id == local_peer_id || swarm.is_connected(&peer_id) {
+                    continue;
+                }
+
+                log::info!("Discovered peer {} through rendezvous at {:?}", peer_id, addresses);
+
+                for address in &addresses {
+                    swarm.behaviour_mut().kademlia.add_address(&peer_id, address.clone());
+                }
+
+                if let Err(e) = swarm.dial(DialOpts::peer_id(peer_id).addresses(addresses).build()) {
+                    log::info!("Failed to dial {}: {}", peer_id, e);
+                }
+            }
+        }
+
+        rendezvous::client::Event::Registered { namespace, ttl, rendezvous_node } => {
+            log::info!("Registered under {} with rendezvous node {} for {}s", namespace, rendezvous_node, ttl);
+        }
+
+        other => {
+            log::info!("{:?}", other);
+        }
+    }
+}
@@ -1,10 +1,16 @@
-use libp2p::request_response::{self};
+use std::collections::HashMap;
+use libp2p::{request_response::{self, OutboundRequestId}, Swarm};
 use libp2p_request_response::Message;
-use crate::state::STATE;
-use crate::network::network::{Request, Response};
+use crate::events::{self, AppEvent};
+use crate::state::{IncomingRequest, STATE};
+use crate::network::{network::{ChatBehaviour, Request, Response}, transfer::Transfers};
+
+/// Protocol files are requested and offered over. The file itself is then fetched over FILE_CHUNK_PROTOCOL (see file_chunk.rs).
+pub const FILE_EXCHANGE_PROTOCOL: &str = "/file-exchange/4";
+
 
 // Handles all Request-Response events that come through the network event loop.
-pub async fn handle_event(event: libp2p::request_response::Event<Request, Response>) {
+pub async fn handle_event(event: libp2p::request_response::Event<Request, Response>, trade_queue: &mut HashMap<OutboundRequestId, String>, transfers: &mut Transfers, swarm: &mut Swarm<ChatBehaviour>) {
 
     match event {
 
@@ -15,31 +21,66 @@ pub async fn handle_event(event: libp2p::request_response::Event<Request, Respon
 
                 // If we receive a request we add it to our global state and this will be shown in the "Incoming Requests" list on
                 // the "File Sharing" tab.
-                Message::Request { request, channel, .. } => {
+                Message::Request { request_id, request, channel } => {
                     log::info!("Received request: {:?}", request);
 
                     let mut state = STATE.lock().unwrap();
-                    state.requests.push((peer, request.message, channel))
+                    let id = state.next_request_id();
+                    events::emit(AppEvent::Request {
+                        request_id: id,
+                        peer: peer.to_string(),
+                        nickname: state.nickname_or_id(&peer),
+                        message: request.message.clone(),
+                    });
+                    state.requests.push(IncomingRequest { id, inbound_id: request_id, peer, message: request.message, trade_id: request.trade_id, channel })
                 },
 
-                // If we recieve a response we write the file to our local directory and proceed to rate the peer.
-                Message::Response { response, .. } => {
-                    log::info!("Received response: {:?}", response);
+                // If we recieve a response the peer has offered us the file, so we start fetching it into our downloads directory (see transfer.rs).
+                // Once it is complete we proceed to rate the peer, as long as they sent a receipt for the trade with the last chunk.
+                Message::Response { request_id, response } => {
+                    log::info!("Offered {} ({} bytes, sha256 {}) by {}", response.filename, response.size, response.sha256, peer);
+
+                    let Some(trade_id) = trade_queue.remove(&request_id) else {
+                        return;
+                    };
 
-                    if let Err(e) = std::fs::write(&response.filename, response.data) {
-                        log::info!("Failed to write file {}: {}", &response.filename, e);
-                    } else {
-                        log::info!("File {} received and saved successfully", &response.filename);
+                    // The request was cancelled while we waited (see transfer.rs)
+                    if !transfers.is_requested(&trade_id) {
+                        return log::info!("Ignoring {} offered for cancelled request {}", response.filename, trade_id);
                     }
 
-                    let mut state = STATE.lock().unwrap();
-                    state.current_rating = Some(peer);
+                    transfers.start_download(swarm, peer, trade_id, response);
                 },
             }
         }
         
-        other => {
-            log::info!("{:?}", other);
+        // The file never came, so there is no trade. It is shown as failed in the Transfers list, where it can be retried.
+        request_response::Event::OutboundFailure { peer, request_id, error } => {
+            log::info!("File request to {} failed: {}", peer, error);
+            if let Some(trade_id) = trade_queue.remove(&request_id) {
+                transfers.request_failed(&trade_id, &error);
+            }
+        }
+
+        // Either our offer of a file never reached the peer who asked for it, or they went before we answered their request
+        request_response::Event::InboundFailure { peer, request_id, error } => {
+            log::info!("File request from {} failed: {}", peer, error);
+            if transfers.on_offer_failed(&request_id) {
+                return;
+            }
+
+            let mut state = STATE.lock().unwrap();
+            let Some(index) = state.requests.iter().position(|request| request.inbound_id == request_id) else {
+                return;
+            };
+            let request = state.requests.remove(index);
+            let nickname = state.nickname_or_id(&peer);
+            let room = state.current_room.clone();
+            state.push_system(&room, format!("⚠️ {} is no longer waiting for \"{}\"", nickname, request.message));
+        }
+
+        request_response::Event::ResponseSent { request_id, .. } => {
+            transfers.on_offer_sent(&request_id);
         }
     }
-}
\ No newline at end of file
+}
@@ -1,9 +1,10 @@
-use libp2p_request_response::ResponseChannel;
-use libp2p::PeerId;
+use libp2p::{Multiaddr, PeerId};
 use futures::channel::mpsc;
 use futures::SinkExt;
 
-use super::{command::Command, network::Response};
+use crate::state::{IncomingRequest, Trade, TransferKind};
+
+use super::command::Command;
 
 /// Used to send commands from the UI to the Network.
 /// For example if a user types a message in the UI to send to the global chat, we must instruct the libp2p
@@ -16,7 +17,7 @@ pub struct Client {
 impl Client {
     
     /// Send a given message the user has typed in the UI.
-    pub(crate) async fn send_message(
+    pub async fn send_message(
         &mut self,
         message: String,
         room: String
@@ -29,7 +30,7 @@ impl Client {
 
 
     /// Request a file from another user.
-    pub(crate) async fn send_request(
+    pub async fn send_request(
         &mut self,
         message: String,
         peer: PeerId
@@ -42,34 +43,34 @@ impl Client {
 
     
     /// Send the file at the given path back to the user who requested it.
-    pub(crate) async fn send_response(
+    pub async fn send_response(
         &mut self,
         filename: String,
         filepath: String,
-        channel: ResponseChannel<Response>
+        request: IncomingRequest
     ) {
         self.sender
-            .send(Command::RespondFile { filename, filepath, channel })
+            .send(Command::RespondFile { filename, filepath, request })
             .await
             .expect("Command receiver not to be dropped.");
     }
 
     /// Update the rating of another peer.
-    /// Called after giving a peer a rating during a file swap to either increase or decrease their rating by 1.
-    pub(crate) async fn update_rating (
+    /// Called after giving a peer a rating for a file swap to either increase or decrease their rating by 1.
+    pub async fn update_rating (
         &mut self,
-        peer: PeerId,
+        trade: Trade,
         rating: i32,
     ) {
         self.sender
-            .send(Command::UpdateRating { peer, rating })
+            .send(Command::UpdateRating { trade, rating })
             .await
             .expect("Command receiver not to be dropped.");
     }
 
 
     /// Create a new room to be shared across the network
-    pub(crate) async fn create_room (
+    pub async fn create_room (
         &mut self,
         name: String,
     ) {
@@ -81,7 +82,7 @@ impl Client {
 
 
     /// Fetch all currently available rooms to join
-    pub(crate) async fn fetch_rooms (
+    pub async fn fetch_rooms (
         &mut self,
     ) {
         self.sender
@@ -89,4 +90,42 @@ impl Client {
             .await
             .expect("Command receiver not to be dropped.");
     }
-}
\ No newline at end of file
+
+
+    /// Connect to a peer at a known address (eg. /ip4/10.0.0.5/tcp/4001/p2p/<PeerId>).
+    pub async fn dial (
+        &mut self,
+        address: Multiaddr,
+    ) {
+        self.sender
+            .send(Command::Dial { address })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+
+    /// Cancel a transfer, or clear it from the Transfers list if it is already over.
+    pub async fn cancel_transfer (
+        &mut self,
+        trade_id: String,
+        kind: TransferKind,
+    ) {
+        self.sender
+            .send(Command::CancelTransfer { trade_id, kind })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+
+    /// Retry a transfer that failed or was cancelled.
+    pub async fn retry_transfer (
+        &mut self,
+        trade_id: String,
+        kind: TransferKind,
+    ) {
+        self.sender
+            .send(Command::RetryTransfer { trade_id, kind })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 
-use libp2p::{gossipsub, kad::{self, store::RecordStore, QueryId}, PeerId, Swarm};
-use libp2p_request_response::ResponseChannel;
+use libp2p::{gossipsub, identity::Keypair, kad::{self, store::RecordStore, QueryId}, multiaddr::Protocol, swarm::{dial_opts::DialOpts, ConnectionId}, Multiaddr, PeerId, Swarm};
+use libp2p_request_response::OutboundRequestId;
 
-use super::network::{ChatBehaviour, Request, Response};
+use crate::{state::{Delivery, IncomingRequest, Message, Trade, TransferKind, STATE}, util};
+
+use super::{message::ChatMessage, network::{ChatBehaviour, Request, Response}, ratings::{self, SignedAttestation}, transfer::{self, Transfers}};
 
 
 /// These are commands that can be called from the UI to instruct the libp2p network to perform an action.
@@ -20,16 +22,27 @@ pub enum Command {
     RespondFile {
         filename: String,
         filepath: String,
-        channel: ResponseChannel<Response>
+        request: IncomingRequest
     },
     UpdateRating {
-        peer: PeerId,
+        trade: Trade,
         rating: i32
     },
     CreateRoom {
         name: String
     },
-    FetchRooms{}
+    FetchRooms{},
+    Dial {
+        address: Multiaddr
+    },
+    CancelTransfer {
+        trade_id: String,
+        kind: TransferKind
+    },
+    RetryTransfer {
+        trade_id: String,
+        kind: TransferKind
+    }
 }
 
 
@@ -40,63 +53,148 @@ pub fn fetch_rooms(swarm: &mut Swarm<ChatBehaviour>) {
 }
 
 
-/// Publish a message to a given topic.
-pub fn send_message(swarm: &mut Swarm<ChatBehaviour>, room: String, message: String) {
-    let topic = gossipsub::IdentTopic::new(room);
-    if let Err(err) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), message.as_bytes()) {
-        log::info!("Error publishing: {:?}", err)
+/// Publish a message to a given topic, or send it directly to the other peer if the room is a DM. It is signed by us, so other
+/// peers can pass it on to whoever missed it (see history_sync.rs).
+pub fn send_message(swarm: &mut Swarm<ChatBehaviour>, keypair: &Keypair, room: String, message: String, dm_queue: &mut HashMap<OutboundRequestId, ChatMessage>) {
+
+    let (nickname, clock) = {
+        let mut state = STATE.lock().unwrap();
+        (state.nickname.clone(), state.tick())
+    };
+    let own_peer_id = *swarm.local_peer_id();
+    let mut message = ChatMessage::new(own_peer_id, nickname.clone(), room.clone(), message, clock);
+    if let Err(e) = message.sign(keypair) {
+        log::info!("Failed to sign message {}: {}", message.id, e);
+    }
+
+    // DMs go straight to the other peer, so nobody else can read them (see direct_message.rs)
+    if let Some(peer) = util::dm_peer(&room, &own_peer_id.to_string()) {
+        let request_id = swarm.behaviour_mut().direct_message.send_request(&peer, message.clone());
+        dm_queue.insert(request_id, message.clone());
+        STATE.lock().unwrap().push_message(Message::chat(message, nickname, Delivery::Pending, None));
+        return;
     }
+
+    let topic = gossipsub::IdentTopic::new(room);
+    let delivery = match message.encode().map(|data| swarm.behaviour_mut().gossipsub.publish(topic, data)) {
+        Ok(Ok(_)) => Delivery::Sent,
+        Ok(Err(err)) => {
+            log::info!("Error publishing: {:?}", err);
+            Delivery::Failed
+        }
+        Err(err) => {
+            log::info!("Failed to encode message: {}", err);
+            Delivery::Failed
+        }
+    };
+
+    // Add to local storage
+    STATE.lock().unwrap().push_message(Message::chat(message, nickname, delivery, None));
 }
 
 
-/// Request a file from another user with a message (eg. Can I have last weeks COSC473 notes).
-pub fn request_file(swarm: &mut Swarm<ChatBehaviour>, message: String, peer: PeerId ) {
-    swarm
+/// Request a file from another user with a message (eg. Can I have last weeks COSC473 notes). The request is given a new trade id,
+/// which we expect the receipt sent with the file to be for (see transfer.rs).
+pub fn request_file(swarm: &mut Swarm<ChatBehaviour>, transfers: &mut Transfers, message: String, peer: PeerId, trade_queue: &mut HashMap<OutboundRequestId, String>) {
+
+    let trade_id = util::random_id();
+    let request_id = swarm
         .behaviour_mut()
         .request_response
-        .send_request(&peer, Request { message });
+        .send_request(&peer, Request { message: message.clone(), trade_id: trade_id.clone() });
+
+    // Shown in the Transfers list until the file is offered (see transfer.rs)
+    transfers.add_request(trade_id.clone(), peer, message);
+    trade_queue.insert(request_id, trade_id);
 }
 
 
-/// Send a file at the given the filepath to the user who requested it.
-pub fn respond_file(swarm: &mut Swarm<ChatBehaviour>, filename: String, filepath: String, channel: ResponseChannel<Response>) {
+/// Offer the file at the given the filepath to the user who requested it. They then fetch the file from us a chunk at a time, and
+/// get a receipt for the trade with the last chunk so they can rate us (see transfer.rs). If the file can't be opened the request is kept, so it can
+/// be answered with another file.
+pub fn respond_file(swarm: &mut Swarm<ChatBehaviour>, transfers: &mut Transfers, filename: String, filepath: String, request: IncomingRequest) {
+
+    let (file, offer) = match transfer::offer(&filepath, filename.clone(), &request.peer) {
+        Ok(opened) => opened,
+        Err(e) => {
+            log::info!("Failed to open {}: {}", filepath, e);
+            let mut state = STATE.lock().unwrap();
+            let room = state.current_room.clone();
+            state.push_system(&room, format!("⚠️ Couldn't open {}", filepath));
+            state.requests.push(request);
+            return;
+        }
+    };
 
-    let data = std::fs::read(&filepath).unwrap_or_else(|_| Vec::new());
-    
-    swarm
-        .behaviour_mut()
-        .request_response
-        .send_response(channel, Response {filename, data })
-        .expect("Connection to peer to be still open.");
+    let response = Response { filename, size: offer.size, sha256: offer.sha256.clone() };
+    transfers.add_upload(request.trade_id.clone(), request.inbound_id, request.peer, file, offer);
+
+    if swarm.behaviour_mut().request_response.send_response(request.channel, response).is_err() {
+        log::info!("Failed to offer {} to {}, they are no longer connected", filepath, request.peer);
+        transfers.remove_upload(&request.trade_id);
+    }
 }
 
 
-/// Update the rating of a peer. Will add it to a queue as the rating first needs to be fetched from the DHT before modifying it. 
-/// The fetch result will come as an OutboundQueryProgressedEvent and the rest of the update will happen after (see kademlia.rs).
-pub fn update_rating(swarm: &mut Swarm<ChatBehaviour>, peer: PeerId, rating: i32, rating_update_queue: &mut HashMap<QueryId, (PeerId, i32)>) {
-    let key_string = "rating_".to_string() + &peer.to_string();
-    let key = kad::RecordKey::new(&key_string);
-    let query_id = swarm.behaviour_mut().kademlia.get_record(key);
-    rating_update_queue.insert(query_id, (peer, rating));
+/// Cancel a transfer in the Transfers list on the "File Sharing" tab, or clear it from the list if it is already over.
+pub fn cancel_transfer(swarm: &mut Swarm<ChatBehaviour>, transfers: &mut Transfers, trade_id: String, kind: TransferKind) {
+    transfers.cancel(swarm, &trade_id, kind);
+}
+
+
+/// Retry a transfer in the Transfers list on the "File Sharing" tab that failed or was cancelled. A file request that never got an
+/// answer is sent again.
+pub fn retry_transfer(swarm: &mut Swarm<ChatBehaviour>, transfers: &mut Transfers, trade_id: String, kind: TransferKind, trade_queue: &mut HashMap<OutboundRequestId, String>) {
+    if let Some((peer, message)) = transfers.retry(swarm, &trade_id, kind) {
+        request_file(swarm, transfers, message, peer, trade_queue);
+    }
+}
+
+
+/// Rate the peer who sent us a file, for that trade. Our (signed) rating is added to a queue as the ratings they already have first need
+/// to be fetched from the DHT before adding to them. The fetch result will come as an OutboundQueryProgressedEvent and the rest of the
+/// update will happen after (see kademlia.rs).
+pub fn update_rating(swarm: &mut Swarm<ChatBehaviour>, keypair: &Keypair, trade: Trade, rating: i32, rating_update_queue: &mut HashMap<QueryId, (PeerId, SignedAttestation)>) {
+
+    let attestation = match SignedAttestation::new(keypair, trade.receipt, rating) {
+        Ok(attestation) => attestation,
+        Err(e) => return log::info!("Failed to sign rating of {}: {}", trade.peer, e),
+    };
+
+    let query_id = swarm.behaviour_mut().kademlia.get_record(ratings::rating_key(&trade.peer));
+    rating_update_queue.insert(query_id, (trade.peer, attestation));
 }
 
 
 /// Create a new room to be shared across the network.
 pub fn create_room(swarm: &mut Swarm<ChatBehaviour>, name: String) {
 
+    if let Err(e) = util::check_room_name(&name) {
+        log::info!("Not creating room: {}", e);
+        let mut state = STATE.lock().unwrap();
+        let room = state.current_room.clone();
+        state.push_system(&room, format!("⚠️ {}", e));
+        return;
+    }
+
     let key = kad::RecordKey::new(&"rooms".to_string());
     let record = swarm.behaviour_mut().kademlia.store_mut().get(&key);
     
-    // If this is the first "created" room, there won't be a "rooms" record in the DHT yet.
-    let rooms = if record.is_none() {
-        vec![name]
-    } else {
-        let mut rooms: Vec<String> = serde_cbor::from_slice(&record.unwrap().value).unwrap();
-        rooms.push(name.clone());
-        rooms
+    // If this is the first "created" room, there won't be a "rooms" record in the DHT yet. One that can't be read (eg. stored
+    // by a broken peer) is replaced.
+    let mut rooms: Vec<String> = match record {
+        None => vec![],
+        Some(record) => serde_cbor::from_slice(&record.value).unwrap_or_else(|e| {
+            log::info!("Replacing unreadable rooms record: {}", e);
+            vec![]
+        }),
     };
+    rooms.push(name);
 
-    let rooms_bytes = serde_cbor::to_vec(&rooms).unwrap();
+    let rooms_bytes = match serde_cbor::to_vec(&rooms) {
+        Ok(bytes) => bytes,
+        Err(e) => return log::info!("Failed to encode rooms: {}", e),
+    };
 
     let record = kad::Record {
         key: kad::RecordKey::new(&key),
@@ -105,6 +203,45 @@ pub fn create_room(swarm: &mut Swarm<ChatBehaviour>, name: String) {
         expires: None,
     };
 
-    swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One).expect("");
-    
-}
\ No newline at end of file
+    if let Err(e) = swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One) {
+        log::info!("Failed to store rooms: {:?}", e);
+    }
+}
+
+
+/// Dial a peer at a known address, eg. a classmate on another subnet that mDNS can't reach. The connection is added to a queue
+/// so that once established the peer can be added to the Kademlia routing table (see event_loop.rs).
+pub fn dial(swarm: &mut Swarm<ChatBehaviour>, address: Multiaddr, dial_queue: &mut HashMap<ConnectionId, Multiaddr>) {
+
+    // If the address includes the peers id we can add it to the routing table straight away.
+    let opts = match peer_id_from_address(&address) {
+        Some(peer_id) => {
+            swarm.behaviour_mut().kademlia.add_address(&peer_id, address.clone());
+            DialOpts::peer_id(peer_id).addresses(vec![address.clone()]).build()
+        }
+        None => DialOpts::unknown_peer_id().address(address.clone()).build(),
+    };
+
+    let connection_id = opts.connection_id();
+
+    match swarm.dial(opts) {
+        Ok(_) => {
+            dial_queue.insert(connection_id, address);
+        }
+        Err(e) => {
+            log::info!("Failed to dial {}: {}", address, e);
+            let mut state = STATE.lock().unwrap();
+            let room = state.current_room.clone();
+            state.push_system(&room, format!("⚠️ Failed to connect to {}: {}", address, e));
+        }
+    }
+}
+
+
+/// Extracts the PeerId from a multiaddr ending in /p2p/<PeerId>, if there is one.
+pub fn peer_id_from_address(address: &Multiaddr) -> Option<PeerId> {
+    match address.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Some(peer_id),
+        _ => None,
+    }
+}
@@ -1,14 +1,24 @@
-use libp2p::{gossipsub, kad::QueryId, swarm::SwarmEvent, Multiaddr, PeerId, Swarm};
+use libp2p::{gossipsub, identity::Keypair, kad::QueryId, request_response::OutboundRequestId, multiaddr::Protocol, swarm::{ConnectionId, SwarmEvent}, Multiaddr, PeerId, Swarm};
 use futures::StreamExt;
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 use futures::channel::mpsc;
 use libp2p::kad;
-use crate::{network::behaviour::mdns as mdns_events, state::STATE};
+use crate::{events::{self, AppEvent}, network::behaviour::mdns as mdns_events, state::{MessageKind, STATE}, util};
 use crate::network::behaviour::gossipsub as gossibsub_events;
 use crate::network::behaviour::kademlia as kademlia_events;
 use crate::network::behaviour::request_response as reqyest_response_events;
+use crate::network::behaviour::identify as identify_events;
+use crate::network::behaviour::history_sync as history_sync_events;
+use crate::network::behaviour::direct_message as direct_message_events;
+use crate::network::behaviour::file_chunk as file_chunk_events;
+use crate::network::behaviour::ping as ping_events;
+use crate::network::behaviour::rendezvous::{self as rendezvous_events, RendezvousPoint};
 
-use super::{client::Client, command::*, network::{ChatBehaviour, ChatBehaviourEvent}};
+use super::{client::Client, command::*, mailbox::{self, Mailbox, MailboxQuery}, message::ChatMessage, ratings::{RatingCache, SignedAttestation, RATINGS_TOPIC, RATING_REFRESH_INTERVAL}, transfer::Transfers, network::{ChatBehaviour, ChatBehaviourEvent}};
+
+
+/// How often we re-register with, and discover new peers from, the rendezvous node.
+const RENDEZVOUS_INTERVAL: Duration = Duration::from_secs(30);
 
 
 /// Defines the libp2p event loop. 
@@ -16,8 +26,18 @@ pub struct EventLoop {
     swarm: Swarm<ChatBehaviour>,
     command_receiver: mpsc::Receiver<Command>,
     nickname_fetch_queue: HashMap<QueryId, (PeerId, String)>, // (PeerId, Dm Key)
-    rating_fetch_queue: HashMap<QueryId, (String, String, String)>, // (Message, Nickname, Topic)
-    rating_update_queue: HashMap<QueryId, (PeerId, i32)> // (PeerId, Recent Rating)
+    rating_update_queue: HashMap<QueryId, (PeerId, SignedAttestation)>, // (PeerId, Our Rating)
+    dial_queue: HashMap<ConnectionId, Multiaddr>, // Manually dialed addresses
+    dm_queue: HashMap<OutboundRequestId, ChatMessage>, // DMs waiting to be acknowledged
+    trade_queue: HashMap<OutboundRequestId, String>, // Trade ids of file requests waiting on the file
+    mailbox_queue: HashMap<QueryId, MailboxQuery>, // Mailbox reads waiting on the DHT
+    mailbox: Mailbox,
+    keypair: Keypair,
+    transfers: Transfers,
+    rating_cache: RatingCache,
+    bootstrap_addresses: Vec<Multiaddr>,
+    rendezvous_point: Option<RendezvousPoint>,
+    is_setup: bool,
 }
 
 
@@ -29,22 +49,42 @@ impl EventLoop {
     pub fn new(
         swarm: Swarm<ChatBehaviour>,
         command_receiver: mpsc::Receiver<Command>,
+        bootstrap_addresses: Vec<Multiaddr>,
+        rendezvous_point: Option<RendezvousPoint>,
+        mailbox: Mailbox,
+        keypair: Keypair,
     ) -> Self {
         Self {
             swarm,
             command_receiver,
             nickname_fetch_queue: HashMap::new(),
-            rating_fetch_queue: HashMap::new(),
             rating_update_queue: HashMap::new(),
+            dial_queue: HashMap::new(),
+            dm_queue: HashMap::new(),
+            trade_queue: HashMap::new(),
+            mailbox_queue: HashMap::new(),
+            mailbox,
+            keypair,
+            transfers: Transfers::load(),
+            rating_cache: RatingCache::default(),
+            bootstrap_addresses,
+            rendezvous_point,
+            is_setup: false,
         }
     }
 
 
     /// Begins the libp2p event loop. To be called from the main application.
     pub async fn run(mut self, client: Client) {
+
+        let mut rendezvous_tick = tokio::time::interval(RENDEZVOUS_INTERVAL);
+        let mut rating_tick = tokio::time::interval(RATING_REFRESH_INTERVAL);
+
         loop {
             tokio::select! {
                 event = self.swarm.select_next_some() => self.handle_event(event, &mut client.clone()).await,
+                _ = rendezvous_tick.tick() => self.refresh_rendezvous(),
+                _ = rating_tick.tick() => self.rating_cache.refresh(&mut self.swarm),
                 command = self.command_receiver.next() => match command {
                     Some(c) => self.handle_command(c).await,
                     None=>  return,
@@ -59,34 +99,126 @@ impl EventLoop {
 
         match event {
 
-            // Initial setup
+            // Initial setup. Runs on the first address to come up, whichever transport it belongs to.
             SwarmEvent::NewListenAddr { address, ..} => {
-                self.setup(address);
+                log::info!("Listening on {}", address);
+                if !self.is_setup {
+                    self.setup();
+                }
+
+                // Show the user the addresses other peers can /connect to
+                let is_loopback = address.iter().any(|p| matches!(p, Protocol::Ip4(ip) if ip.is_loopback()) || matches!(p, Protocol::Ip6(ip) if ip.is_loopback()));
+                if !is_loopback {
+
+                    // Registering with a rendezvous node requires addresses others can reach us on.
+                    if self.rendezvous_point.is_some() {
+                        self.swarm.add_external_address(address.clone());
+                    }
+
+                    let peer_id = *self.swarm.local_peer_id();
+                    let mut state = STATE.lock().unwrap();
+                    state.push_system("Global", format!("📡 Reachable at {}/p2p/{}", address, peer_id));
+                }
+            },
+
+            // Handles a new connection. Peers found through mDNS are already known, but peers that dialed us (or that we dialed)
+            // from another network need to be added. Manually dialed peers also seed the Kademlia routing table.
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+
+                // The rendezvous node isn't a Swapbytes user, so we only register with it rather than adding it as a peer.
+                if let Some(point) = self.rendezvous_point.as_ref().filter(|point| point.peer_id == peer_id) {
+                    point.register_and_discover(&mut self.swarm);
+                    return;
+                }
+
+                if let Some(address) = self.dial_queue.remove(&connection_id) {
+                    log::info!("Connected to {} at {}", peer_id, address);
+                    self.swarm.behaviour_mut().kademlia.add_address(&peer_id, endpoint.get_remote_address().clone());
+
+                    if let Err(e) = self.swarm.behaviour_mut().kademlia.bootstrap() {
+                        log::info!("Kademlia bootstrap failed: {:?}", e);
+                    }
+                }
+
+                // Only the first connection to a peer means they have joined, further connections (eg. over another transport) don't.
+                if num_established.get() == 1 {
+                    add_peer(peer_id, &mut self.swarm, &mut self.nickname_fetch_queue);
+
+                    // They may be holding DMs sent to us while we were offline
+                    mailbox::collect(&mut self.swarm, &mut self.mailbox_queue);
+
+                    // Or be part way through sending us a file
+                    self.transfers.resume(&mut self.swarm, &peer_id);
+                }
+            },
+
+            // Handles a failed manual dial by letting the user know.
+            SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+
+                if let Some(address) = self.dial_queue.remove(&connection_id) {
+                    log::info!("Failed to connect to {}: {}", address, error);
+                    let mut state = STATE.lock().unwrap();
+                    let room = state.current_room.clone();
+                    state.push_system(&room, format!("⚠️ Failed to connect to {}", address));
+                }
             },
 
-            // Handles the event that a known peer disconnects.
-            SwarmEvent::ConnectionClosed { peer_id, ..} => {
-                self.remove_peer(peer_id);
+            // Handles the event that a known peer disconnects. A peer has only gone once their last connection has closed.
+            SwarmEvent::ConnectionClosed { peer_id, num_established, cause, .. } => {
+                log::info!("Connection to {} closed ({} remaining): {:?}", peer_id, num_established, cause);
+                if num_established == 0 {
+                    remove_peer(peer_id, &mut self.swarm);
+                }
             },
 
             // Handle MDNS (Peer Connection) events
             SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(event)) => {
-                mdns_events::handle_event(event, &mut self.swarm, &mut self.nickname_fetch_queue).await;
+                mdns_events::handle_event(event, &mut self.swarm).await;
             }
 
             // Handle Gossipsub (Message) events
             SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(event)) => {
-                gossibsub_events::handle_event(event, &mut self.rating_fetch_queue, &mut self.swarm).await;
+                gossibsub_events::handle_event(event, &mut self.rating_cache, &mut self.swarm).await;
             }
 
             // Handle Kademlia (Stored DHT) events
             SwarmEvent::Behaviour(ChatBehaviourEvent::Kademlia(event)) => {
-                kademlia_events::handle_event(event, &mut self.nickname_fetch_queue, &mut self.rating_fetch_queue, &mut self.rating_update_queue, &mut self.swarm).await;
+                kademlia_events::handle_event(event, &mut self.nickname_fetch_queue, &mut self.rating_cache, &mut self.rating_update_queue, &mut self.mailbox_queue, &self.mailbox, &mut self.swarm).await;
             }
     
+            // Handle Rendezvous (Peer Discovery) events
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Rendezvous(event)) => {
+                rendezvous_events::handle_event(event, &mut self.swarm, &mut self.rendezvous_point).await;
+            }
+
+            // Handle Identify (Peer Information) events
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(event)) => {
+                identify_events::handle_event(event, &mut self.swarm).await;
+            }
+
+            // Handle Ping (Liveness) events
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Ping(event)) => {
+                ping_events::handle_event(event, &mut self.swarm).await;
+            }
+
+            // Handle History Sync (Missed Messages) events
+            SwarmEvent::Behaviour(ChatBehaviourEvent::HistorySync(event)) => {
+                history_sync_events::handle_event(event, &mut self.rating_cache, &mut self.swarm).await;
+            }
+
+            // Handle Direct Message (Private Chat) events
+            SwarmEvent::Behaviour(ChatBehaviourEvent::DirectMessage(event)) => {
+                direct_message_events::handle_event(event, &mut self.dm_queue, &mut self.rating_cache, &mut self.mailbox_queue, &self.mailbox, &mut self.swarm).await;
+            }
+
             // Handle Request-Response (File-Sharing) events
             SwarmEvent::Behaviour(ChatBehaviourEvent::RequestResponse(event)) => {
-                reqyest_response_events::handle_event(event).await;
+                reqyest_response_events::handle_event(event, &mut self.trade_queue, &mut self.transfers, &mut self.swarm).await;
+            }
+
+            // Handle File Chunk (File Transfer) events
+            SwarmEvent::Behaviour(ChatBehaviourEvent::FileChunk(event)) => {
+                file_chunk_events::handle_event(event, &mut self.transfers, &self.keypair, &mut self.swarm).await;
             }
 
             other => {
@@ -112,84 +244,146 @@ impl EventLoop {
             }
 
             Command::SendMessage { message , room} => {
-                send_message(&mut self.swarm, room, message)
+                send_message(&mut self.swarm, &self.keypair, room, message, &mut self.dm_queue)
             }
 
             Command::RequestFile {message, peer} => {
-                request_file(&mut self.swarm, message, peer);
+                request_file(&mut self.swarm, &mut self.transfers, message, peer, &mut self.trade_queue);
+            }
+
+            Command::RespondFile { filename, filepath, request } => {
+                respond_file(&mut self.swarm, &mut self.transfers, filename, filepath, request);
             }
 
-            Command::RespondFile { filename, filepath, channel } => {
-                respond_file(&mut self.swarm, filename, filepath, channel);
+            Command::UpdateRating { trade, rating } => {
+                update_rating(&mut self.swarm, &self.keypair, trade, rating, &mut self.rating_update_queue)
             }
 
-            Command::UpdateRating { peer, rating } => {
-                update_rating(&mut self.swarm, peer, rating, &mut self.rating_update_queue)
+            Command::Dial { address } => {
+                dial(&mut self.swarm, address, &mut self.dial_queue)
+            }
+
+            Command::CancelTransfer { trade_id, kind } => {
+                cancel_transfer(&mut self.swarm, &mut self.transfers, trade_id, kind)
+            }
+
+            Command::RetryTransfer { trade_id, kind } => {
+                retry_transfer(&mut self.swarm, &mut self.transfers, trade_id, kind, &mut self.trade_queue)
             }
         }
     }
 
 
-    /// Removes a known peer from our storage when they disconnect.
-    fn remove_peer(&mut self, peer_id: PeerId) {
+    /// Keeps us registered with the rendezvous node (if there is one) and picks up peers that registered since we last asked.
+    /// If we aren't connected to the node, dial it and the registration will happen once the connection is established.
+    fn refresh_rendezvous(&mut self) {
 
-        // Remove from network
-        self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-        self.swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
-        
-        // Remove from local list
-        let mut state = STATE.lock().unwrap();
-        state.peers.retain(|&x| x != peer_id);
+        let Some(point) = &self.rendezvous_point else {
+            return;
+        };
+
+        if self.swarm.is_connected(&point.peer_id) {
+            point.register_and_discover(&mut self.swarm);
+        } else if let Err(e) = self.swarm.dial(point.address.clone()) {
+            log::info!("Failed to dial rendezvous node {}: {}", point.address, e);
+        }
     }
 
 
     // Sets up a user when they first join the network.
-    fn setup(&mut self, address: Multiaddr) {
+    fn setup(&mut self) {
 
-        if address.to_string().contains("/ip4/127.0.0.1/udp") {
+        self.is_setup = true;
+        let peer_id = *self.swarm.local_peer_id();
 
-            let peer_id = self.swarm.local_peer_id().clone();
-            self.swarm.behaviour_mut().kademlia.add_address(&peer_id, address);
+        // Add your nickname to DHT
+        let mut state= STATE.lock().unwrap();
+        let nickname_bytes = serde_cbor::to_vec(&state.nickname).unwrap();
+        let key = "nickname_".to_string() + &peer_id.to_string();
 
-            // Add your nickname to DHT
-            let mut state= STATE.lock().unwrap();
-            let nickname_bytes = serde_cbor::to_vec(&state.nickname).unwrap();
-            let key = "nickname_".to_string() + &peer_id.to_string();
+        let record = kad::Record {
+            key: kad::RecordKey::new(&key),
+            value: nickname_bytes,
+            publisher: None,
+            expires: None,
+        };
 
-            let record = kad::Record {
-                key: kad::RecordKey::new(&key),
-                value: nickname_bytes,
-                publisher: None,
-                expires: None,
-            };
+        self.swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One).expect("Failed to store record");
 
-            self.swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One).expect("Failed to store record");
+        // Connect to the default rooms
+        let default_rooms = &mut vec!["Global".to_string(), "COSC473".to_string(), "COSC478".to_string(), "SENG406".to_string(), "SENG402".to_string()];
+        for room in default_rooms {
+            let topic = gossipsub::IdentTopic::new(room.to_string());
+            self.swarm.behaviour_mut().gossipsub.subscribe(&topic).expect("");
 
-            // Add your peer rating to DHT
-            let key = "rating_".to_string() + &peer_id.to_string();
-            let rating_bytes = serde_cbor::to_vec(&0).unwrap();
+            state.open_room(room, format!("✨ Welcome to the {} chat!", &room));
+        }
+        drop(state);
 
-            let record = kad::Record {
-                key: kad::RecordKey::new(&key),
-                value: rating_bytes,
-                publisher: None,
-                expires: None,
-            };
+        // Hear about rating changes, to keep our cached ratings fresh (see ratings.rs)
+        let topic = gossipsub::IdentTopic::new(RATINGS_TOPIC);
+        self.swarm.behaviour_mut().gossipsub.subscribe(&topic).expect("");
 
-            self.swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One).expect("Failed to store record");
+        // Connect to the configured bootstrap peers (peers outside of our local network)
+        for address in std::mem::take(&mut self.bootstrap_addresses) {
+            dial(&mut self.swarm, address, &mut self.dial_queue);
+        }
+    }
+}
 
 
-            // Connect to the default rooms
-            let default_rooms = &mut vec!["Global".to_string(), "COSC473".to_string(), "COSC478".to_string(), "SENG406".to_string(), "SENG402".to_string()];
-            for room in default_rooms {
-                let topic = gossipsub::IdentTopic::new(room.to_string());
-                self.swarm.behaviour_mut().gossipsub.subscribe(&topic).expect("");
+/// Adds a newly connected peer to our network and local storage, whether found through mDNS, rendezvous or a direct connection.
+/// Subscribes to the DM for this user and fetches their nickname from the DHT.
+pub(crate) fn add_peer(peer_id: PeerId, swarm: &mut Swarm<ChatBehaviour>, nickname_fetch_queue: &mut HashMap<QueryId, (PeerId, String)>) {
 
-                state.messages.entry(room.clone()).or_insert(vec![]);
-    
-                let msgs = state.messages.entry(room.clone()).or_default();
-                msgs.push(format!("✨ Welcome to the {} chat!", &room));
-            }   
-        }
+    let mut state = STATE.lock().unwrap();
+
+    if state.peers.contains(&peer_id) {
+        return;
     }
-}
\ No newline at end of file
+
+    log::info!("Connected with person with id: {peer_id}");
+
+    // Add the peer to our network
+    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+
+    // Update local peers. If we already know their nickname (they have been here before) let everyone know they're back,
+    // otherwise this happens once identify tells us who they are (see identify.rs).
+    state.peers.push(peer_id);
+    if let Some(nickname) = state.nicknames.get(&peer_id.to_string()).cloned() {
+        state.announce(MessageKind::Join, format!("👋 {} joined", nickname));
+        events::emit(AppEvent::PeerJoined { peer: peer_id.to_string(), nickname });
+    }
+
+    // The DM with this user (messages are sent directly to them, see direct_message.rs)
+    let dm_key = util::format_dm_key(peer_id.to_string(), state.peer_id.clone());
+
+    // Fetch the users nickname from the DHT
+    let key_string = "nickname_".to_string() + &peer_id.to_string();
+    let key = kad::RecordKey::new(&key_string);
+    let query_id = swarm.behaviour_mut().kademlia.get_record(key);
+    nickname_fetch_queue.insert(query_id, (peer_id, dm_key));
+}
+
+
+/// Removes a known peer from our network and local storage once they have really gone (their last connection has closed).
+pub(crate) fn remove_peer(peer_id: PeerId, swarm: &mut Swarm<ChatBehaviour>) {
+
+    let mut state = STATE.lock().unwrap();
+
+    if !state.peers.contains(&peer_id) {
+        return;
+    }
+
+    // Remove from network
+    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+    swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+
+    // Remove from local list
+    state.peers.retain(|&x| x != peer_id);
+    state.peer_health.remove(&peer_id.to_string());
+    if let Some(nickname) = state.nicknames.get(&peer_id.to_string()).cloned() {
+        state.announce(MessageKind::Leave, format!("🚪 {} left", nickname));
+        events::emit(AppEvent::PeerLeft { peer: peer_id.to_string(), nickname });
+    }
+}
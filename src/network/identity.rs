@@ -0,0 +1,132 @@
+use std::{error::Error, fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}};
+use libp2p::{identity::{Keypair, PublicKey}, PeerId};
+
+/// Name of the file (inside the data directory) holding the peers ed25519 keypair.
+pub const IDENTITY_FILE: &str = "identity.key";
+
+
+/// Location of the identity file within the given data directory.
+pub fn identity_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(IDENTITY_FILE)
+}
+
+
+/// Loads the keypair stored in the data directory, generating and saving a new one on first launch.
+/// Keeping the same keypair means we keep the same PeerId, and therefore our rating, nickname and DMs between sessions.
+pub fn load_or_generate(data_dir: &Path) -> Result<Keypair, Box<dyn Error>> {
+
+    let path = identity_path(data_dir);
+
+    if path.exists() {
+        let keypair = read_keypair(&path)?;
+        log::info!("Loaded identity {} from {}", keypair.public().to_peer_id(), path.display());
+        Ok(keypair)
+    } else {
+        generate(data_dir, false)
+    }
+}
+
+
+/// Generates a new ed25519 keypair and saves it to the data directory.
+/// Refuses to replace an existing identity unless `force` is set, as the old PeerId would be lost for good.
+pub fn generate(data_dir: &Path, force: bool) -> Result<Keypair, Box<dyn Error>> {
+
+    let path = identity_path(data_dir);
+
+    if path.exists() && !force {
+        return Err(format!("An identity already exists at {}", path.display()).into());
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    write_keypair(&path, &keypair)?;
+    log::info!("Generated identity {} at {}", keypair.public().to_peer_id(), path.display());
+
+    Ok(keypair)
+}
+
+
+/// Copies the current identity to the given file so it can be backed up or moved to another machine.
+pub fn export(data_dir: &Path, destination: &Path) -> Result<Keypair, Box<dyn Error>> {
+
+    let keypair = read_keypair(&identity_path(data_dir))?;
+    write_keypair(destination, &keypair)?;
+
+    Ok(keypair)
+}
+
+
+/// Replaces the current identity with the keypair in the given file (as created by `export`).
+pub fn import(data_dir: &Path, source: &Path) -> Result<Keypair, Box<dyn Error>> {
+
+    let keypair = read_keypair(source)?;
+    write_keypair(&identity_path(data_dir), &keypair)?;
+
+    Ok(keypair)
+}
+
+
+/// The public key of a peer, which for ed25519 identities is contained in the PeerId itself. Used to check things they
+/// have signed (eg. DMs left in a mailbox, or ratings).
+pub fn public_key(peer_id: &PeerId) -> Result<PublicKey, Box<dyn Error>> {
+
+    let multihash = peer_id.as_ref();
+    if multihash.code() != 0 {
+        return Err(format!("PeerId {} doesn't contain its public key", peer_id).into());
+    }
+
+    Ok(PublicKey::try_decode_protobuf(multihash.digest())?)
+}
+
+
+/// Reads a protobuf encoded keypair from disk. Only ed25519 identities are accepted.
+fn read_keypair(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read identity {}: {}", path.display(), e))?;
+    let keypair = Keypair::from_protobuf_encoding(&bytes)?;
+
+    if keypair.clone().try_into_ed25519().is_err() {
+        return Err(format!("Identity {} is not an ed25519 keypair", path.display()).into());
+    }
+
+    Ok(keypair)
+}
+
+
+/// Writes a protobuf encoded keypair to disk, only readable by the current user. It is written to a new file created that
+/// way (so the key is never readable by anyone else, even briefly) and then moved into place, so an existing identity is only
+/// replaced once the new one is complete.
+fn write_keypair(path: &Path, keypair: &Keypair) -> Result<(), Box<dyn Error>> {
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let bytes = keypair.to_protobuf_encoding()?;
+
+    // Left behind if we were stopped while writing it
+    let temporary = path.with_extension("tmp");
+    match fs::remove_file(&temporary) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut file = create_private(&temporary)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+
+    Ok(())
+}
+
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
@@ -0,0 +1,551 @@
+use std::{collections::HashMap, error::Error};
+use chrono::Utc;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use libp2p::{identity::Keypair, kad::{self, store::{MemoryStore, RecordStore}, QueryId}, PeerId, Swarm};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{events::{self, AppEvent}, state::{self, Delivery, STATE}, util};
+
+use super::{identity::public_key, message::ChatMessage, network::ChatBehaviour};
+
+/// Most DMs kept in a single mailbox, so one peer can't fill up the DHT. The oldest are dropped first.
+const MAX_MAILBOX_MESSAGES: usize = 50;
+
+/// Most ids of collected DMs a mailbox remembers, so a peer who read the mailbox before they were collected can't add them back.
+const MAX_REMOVED: usize = 200;
+
+/// Domain separation for the key used to encrypt mailbox messages.
+const MAILBOX_KEY_CONTEXT: &[u8] = b"swapbytes-mailbox-v1";
+
+
+/// A DM waiting in the DHT for a peer who was offline when it was sent. Only the recipient can read it: the message is
+/// encrypted with a key agreed between a one-off (ephemeral) X25519 key and the recipients identity, and it is signed by the
+/// sender so the recipient knows who it is really from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMessage {
+    /// Id of the message inside, so it can be removed once collected without having to read it.
+    pub id: String,
+    pub ephemeral: [u8; 32],
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+
+/// What is inside a SealedMessage.
+#[derive(Serialize, Deserialize)]
+struct SignedMessage {
+    message: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+
+/// A mailbox as stored in the DHT. Peers storing it only ever add DMs to it (see merge_record), so the only way DMs leave
+/// it is by its owner collecting them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MailboxRecord {
+    pub messages: Vec<SealedMessage>,
+    /// The DMs the owner has collected, which are taken out of the mailbox wherever it is stored.
+    pub removed: Option<SignedRemoval>,
+}
+
+
+/// Ids of the DMs the owner of a mailbox has collected from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Removal {
+    /// PeerId of the owner of the mailbox.
+    owner: String,
+    ids: Vec<String>,
+    /// Milliseconds since the Unix epoch. The latest removal replaces any earlier one.
+    issued: i64,
+}
+
+
+/// A Removal signed by the owner of the mailbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedRemoval {
+    removal: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+
+impl SignedRemoval {
+
+    /// Reads the removal, checking it was signed by the owner of the given mailbox.
+    fn verify(&self, owner: &PeerId) -> Result<Removal, Box<dyn Error>> {
+
+        let removal: Removal = serde_cbor::from_slice(&self.removal)?;
+
+        if removal.owner != owner.to_string() {
+            return Err(format!("Removal from mailbox of {} is for mailbox of {}", removal.owner, owner).into());
+        }
+        if !public_key(owner)?.verify(&self.removal, &self.signature) {
+            return Err(format!("Bad signature on removal from mailbox of {}", owner).into());
+        }
+
+        Ok(removal)
+    }
+}
+
+
+/// A mailbox query in progress, waiting on the current contents of a mailbox record.
+pub enum MailboxQuery {
+    /// Add a DM we couldn't deliver to the recipients mailbox. (Sealed message, room, message id)
+    Store(SealedMessage, String, String),
+    /// Read our own mailbox.
+    Collect,
+    /// Remove the DMs with these ids from our own mailbox, now that we have them.
+    Clear(Vec<String>),
+}
+
+
+/// Our keys for sealing DMs into other peers mailboxes and opening the ones in our own.
+pub struct Mailbox {
+    keypair: Keypair,
+    secret: StaticSecret,
+}
+
+
+impl Mailbox {
+
+    /// Derives our mailbox keys from our (ed25519) identity.
+    pub fn new(keypair: &Keypair) -> Result<Mailbox, Box<dyn Error>> {
+
+        let ed25519 = keypair.clone().try_into_ed25519()?;
+
+        // The same conversion used by ed25519 -> X25519 tools (eg. libsodium): the scalar is the first half of the SHA-512
+        // of the ed25519 seed (clamped by StaticSecret).
+        let hash = Sha512::digest(ed25519.secret().as_ref());
+        let mut scalar = [0u8; 32];
+        scalar.copy_from_slice(&hash[..32]);
+
+        Ok(Mailbox { keypair: keypair.clone(), secret: StaticSecret::from(scalar) })
+    }
+
+
+    /// Encrypts (and signs) a DM so only the given peer can read it.
+    pub fn seal(&self, recipient: &PeerId, message: &ChatMessage) -> Result<SealedMessage, Box<dyn Error>> {
+
+        let recipient_key = x25519_public_key(recipient)?;
+
+        let mut ephemeral_bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut ephemeral_bytes);
+        let ephemeral = StaticSecret::from(ephemeral_bytes);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+
+        let cipher = cipher(ephemeral.diffie_hellman(&recipient_key).as_bytes(), &ephemeral_public, &recipient_key);
+
+        let encoded = message.encode()?;
+        let signature = self.keypair.sign(&encoded)?;
+        let plaintext = serde_cbor::to_vec(&SignedMessage { message: encoded, signature })?;
+
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_slice()).map_err(|_| "Failed to encrypt message")?;
+
+        Ok(SealedMessage { id: message.id.clone(), ephemeral: ephemeral_public.to_bytes(), nonce, ciphertext })
+    }
+
+
+    /// Signs the ids of the DMs we have collected from our mailbox, so they are taken out of it.
+    fn remove(&self, ids: Vec<String>) -> Result<SignedRemoval, Box<dyn Error>> {
+
+        let removal = Removal { owner: self.keypair.public().to_peer_id().to_string(), ids, issued: Utc::now().timestamp_millis() };
+        let removal = serde_cbor::to_vec(&removal)?;
+        let signature = self.keypair.sign(&removal)?;
+
+        Ok(SignedRemoval { removal, signature })
+    }
+
+
+    /// Decrypts a DM from our mailbox, checking it was signed by the peer it claims to be from.
+    pub fn open(&self, sealed: &SealedMessage) -> Result<ChatMessage, Box<dyn Error>> {
+
+        let ephemeral = PublicKey::from(sealed.ephemeral);
+        let own_key = PublicKey::from(&self.secret);
+        let cipher = cipher(self.secret.diffie_hellman(&ephemeral).as_bytes(), &ephemeral, &own_key);
+
+        let plaintext = cipher.decrypt(Nonce::from_slice(&sealed.nonce), sealed.ciphertext.as_slice()).map_err(|_| "Failed to decrypt message")?;
+        let signed: SignedMessage = serde_cbor::from_slice(&plaintext)?;
+        let message = ChatMessage::decode(&signed.message)?;
+
+        let sender: PeerId = message.sender.parse()?;
+        if !public_key(&sender)?.verify(&signed.message, &signed.signature) {
+            return Err(format!("Bad signature on message from {}", sender).into());
+        }
+
+        Ok(message)
+    }
+}
+
+
+/// The symmetric cipher for a mailbox message, keyed from the X25519 shared secret and both public keys.
+fn cipher(shared_secret: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+
+    let key = Sha256::new()
+        .chain_update(MAILBOX_KEY_CONTEXT)
+        .chain_update(shared_secret)
+        .chain_update(ephemeral.as_bytes())
+        .chain_update(recipient.as_bytes())
+        .finalize();
+
+    ChaCha20Poly1305::new(&key)
+}
+
+
+/// The X25519 form of a peers ed25519 public key, for agreeing a key with them.
+fn x25519_public_key(peer_id: &PeerId) -> Result<PublicKey, Box<dyn Error>> {
+
+    let ed25519 = public_key(peer_id)?.try_into_ed25519()?;
+    let point = CompressedEdwardsY(ed25519.to_bytes()).decompress().ok_or("Invalid ed25519 public key")?;
+
+    Ok(PublicKey::from(point.to_montgomery().to_bytes()))
+}
+
+
+/// The DHT key of a peers mailbox.
+pub fn mailbox_key(peer_id: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("mailbox_{}", peer_id))
+}
+
+
+/// Seals a DM that couldn't be delivered and adds it to the recipients mailbox, for them to collect when they are next online.
+pub fn store(swarm: &mut Swarm<ChatBehaviour>, mailbox: &Mailbox, mailbox_queue: &mut HashMap<QueryId, MailboxQuery>, recipient: PeerId, message: &ChatMessage) {
+
+    let sealed = match mailbox.seal(&recipient, message) {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            log::info!("Failed to seal DM {} for {}: {}", message.id, recipient, e);
+            STATE.lock().unwrap().set_delivery(&message.room, &message.id, Delivery::Failed);
+            return;
+        }
+    };
+
+    // Mailboxes hold several messages, so the current one has to be read before it can be added to (see on_record).
+    let query_id = swarm.behaviour_mut().kademlia.get_record(mailbox_key(&recipient));
+    mailbox_queue.insert(query_id, MailboxQuery::Store(sealed, message.room.clone(), message.id.clone()));
+}
+
+
+/// Looks for DMs left in our mailbox while we were offline. Called as peers connect, as any of them may be holding it.
+pub fn collect(swarm: &mut Swarm<ChatBehaviour>, mailbox_queue: &mut HashMap<QueryId, MailboxQuery>) {
+
+    // One look at a time is enough
+    if mailbox_queue.values().any(|query| matches!(query, MailboxQuery::Collect | MailboxQuery::Clear(_))) {
+        return;
+    }
+
+    let own_peer_id = *swarm.local_peer_id();
+    let query_id = swarm.behaviour_mut().kademlia.get_record(mailbox_key(&own_peer_id));
+    mailbox_queue.insert(query_id, MailboxQuery::Collect);
+}
+
+
+/// Handles the contents of a mailbox coming back from the DHT (None if there is no mailbox yet).
+pub fn on_record(
+    swarm: &mut Swarm<ChatBehaviour>,
+    mailbox: &Mailbox,
+    mailbox_queue: &mut HashMap<QueryId, MailboxQuery>,
+    query: MailboxQuery,
+    key: kad::RecordKey,
+    record: Option<MailboxRecord>
+    ) {
+
+    let mut record = record.unwrap_or_default();
+
+    match query {
+
+        MailboxQuery::Store(sealed, room, id) => {
+            if !record.messages.iter().any(|message| message.id == sealed.id) {
+                if record.messages.len() >= MAX_MAILBOX_MESSAGES {
+                    log::info!("Mailbox for DM {} is full", id);
+                    return STATE.lock().unwrap().set_delivery(&room, &id, Delivery::Failed);
+                }
+                record.messages.push(sealed);
+            }
+
+            let delivery = if put_mailbox(swarm, key, &record) { Delivery::Queued } else { Delivery::Failed };
+            STATE.lock().unwrap().set_delivery(&room, &id, delivery);
+        }
+
+        MailboxQuery::Collect => {
+            if record.messages.is_empty() {
+                return;
+            }
+
+            let mut collected = vec![];
+            for sealed in &record.messages {
+                match mailbox.open(sealed) {
+                    Ok(message) => collected.push(message),
+                    Err(e) => log::info!("Dropping unreadable mailbox message {}: {}", sealed.id, e),
+                }
+            }
+
+            let ids = record.messages.into_iter().map(|message| message.id).collect();
+            deliver(swarm, collected);
+
+            // Read the mailbox again right before clearing it, so DMs added in the meantime aren't lost.
+            let query_id = swarm.behaviour_mut().kademlia.get_record(key);
+            mailbox_queue.insert(query_id, MailboxQuery::Clear(ids));
+        }
+
+        MailboxQuery::Clear(ids) => {
+            let count = record.messages.len();
+            record.messages.retain(|message| !ids.contains(&message.id));
+            if record.messages.len() == count {
+                return;
+            }
+
+            // Earlier removals are kept too, in case a peer who read the mailbox before them stores it again
+            let own_peer_id = *swarm.local_peer_id();
+            let mut removed = record.removed.and_then(|removed| removed.verify(&own_peer_id).ok()).map(|removal| removal.ids).unwrap_or_default();
+            removed.extend(ids);
+            removed.drain(..removed.len().saturating_sub(MAX_REMOVED));
+
+            match mailbox.remove(removed) {
+                Ok(removal) => record.removed = Some(removal),
+                Err(e) => return log::info!("Failed to sign removal from our mailbox: {}", e),
+            }
+            put_mailbox(swarm, key, &record);
+        }
+    }
+}
+
+
+/// Merges a mailbox another peer has asked us to store with the one we already have, returning the record to keep. Nobody but
+/// the owner can take DMs out of it: every DM we have is kept unless the owner's latest (signed) removal lists it, and new DMs
+/// are only added while there is room, so two DMs stored at the same time are both kept, and a full mailbox can't be emptied
+/// by filling it with others.
+pub fn merge_record(store: &MemoryStore, owner: &PeerId, record: kad::Record, incoming: MailboxRecord) -> Result<kad::Record, Box<dyn Error>> {
+
+    let stored: MailboxRecord = match store.get(&record.key) {
+        Some(stored) => serde_cbor::from_slice(&stored.value).unwrap_or_default(),
+        None => MailboxRecord::default(),
+    };
+
+    let removed = [stored.removed, incoming.removed].into_iter()
+        .flatten()
+        .filter_map(|signed| match signed.verify(owner) {
+            Ok(removal) => Some((removal, signed)),
+            Err(e) => {
+                log::info!("Ignoring removal from mailbox: {}", e);
+                None
+            }
+        })
+        .max_by_key(|(removal, _)| removal.issued);
+    let removed_ids = removed.as_ref().map(|(removal, _)| removal.ids.clone()).unwrap_or_default();
+
+    let mut messages: Vec<SealedMessage> = vec![];
+    for message in stored.messages.into_iter().chain(incoming.messages) {
+        if messages.len() < MAX_MAILBOX_MESSAGES && !removed_ids.contains(&message.id) && !messages.iter().any(|kept| kept.id == message.id) {
+            messages.push(message);
+        }
+    }
+
+    let merged = MailboxRecord { messages, removed: removed.map(|(_, signed)| signed) };
+    Ok(kad::Record { value: serde_cbor::to_vec(&merged)?, ..record })
+}
+
+
+/// Handles a mailbox that couldn't be read (other than because it doesn't exist yet).
+pub fn on_failure(query: MailboxQuery) {
+    if let MailboxQuery::Store(_, room, id) = query {
+        STATE.lock().unwrap().set_delivery(&room, &id, Delivery::Failed);
+    }
+}
+
+
+/// Writes a mailbox back to the DHT. Returns whether it could be stored.
+fn put_mailbox(swarm: &mut Swarm<ChatBehaviour>, key: kad::RecordKey, mailbox: &MailboxRecord) -> bool {
+
+    let value = match serde_cbor::to_vec(mailbox) {
+        Ok(value) => value,
+        Err(e) => {
+            log::info!("Failed to encode mailbox: {}", e);
+            return false;
+        }
+    };
+
+    let record = kad::Record {
+        key,
+        value,
+        publisher: None,
+        expires: None,
+    };
+
+    match swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One) {
+        Ok(_) => true,
+        Err(e) => {
+            log::info!("Failed to store mailbox: {:?}", e);
+            false
+        }
+    }
+}
+
+
+/// Adds DMs collected from our mailbox to their conversations.
+fn deliver(swarm: &mut Swarm<ChatBehaviour>, messages: Vec<ChatMessage>) {
+
+    let own_peer_id = swarm.local_peer_id().to_string();
+    let mut state = STATE.lock().unwrap();
+
+    for mut message in messages {
+
+        let Ok(sender) = message.sender.parse::<PeerId>() else {
+            continue;
+        };
+
+        message.room = util::format_dm_key(sender.to_string(), own_peer_id.clone());
+        let room = message.room.clone();
+        let nickname = state.nicknames.get(&message.sender).cloned().unwrap_or_else(|| message.nickname.clone());
+
+        let event = AppEvent::Message { room: room.clone(), peer: message.sender.clone(), nickname: nickname.clone(), message: message.body.clone() };
+        state.open_room(&room, format!("😀 Chatting with {}", nickname));
+
+        // Skip any we already have (eg. if a previous collection wasn't cleared)
+        if state.merge_messages(&room, vec![state::Message::chat(message, nickname, Delivery::Received, None)]) > 0 {
+            state.notifications.insert(room, true);
+            events::emit(event);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use libp2p::kad::store::MemoryStoreConfig;
+
+    use crate::network::network::MAX_RECORD_SIZE;
+
+    use super::*;
+
+    fn message(sender: &Keypair, recipient: &PeerId, body: &str) -> ChatMessage {
+        let sender = sender.public().to_peer_id();
+        ChatMessage::new(sender, "alice".to_string(), util::format_dm_key(sender.to_string(), recipient.to_string()), body.to_string(), 1)
+    }
+
+
+    /// A DM sealed for the given peer, from a new peer.
+    fn sealed(recipient: &PeerId) -> SealedMessage {
+        let sender = Keypair::generate_ed25519();
+        Mailbox::new(&sender).unwrap().seal(recipient, &message(&sender, recipient, "hello")).unwrap()
+    }
+
+
+    /// A removal of the given DMs, signed at the given time.
+    fn removal(owner: &Keypair, ids: &[&str], issued: i64) -> SignedRemoval {
+        let removal = Removal { owner: owner.public().to_peer_id().to_string(), ids: ids.iter().map(|id| id.to_string()).collect(), issued };
+        let removal = serde_cbor::to_vec(&removal).unwrap();
+        let signature = owner.sign(&removal).unwrap();
+        SignedRemoval { removal, signature }
+    }
+
+
+    /// Merges a mailbox being stored into the given one, returning the ids of the DMs kept.
+    fn merge(owner: &PeerId, stored: &MailboxRecord, incoming: MailboxRecord) -> Vec<String> {
+
+        let mut store = MemoryStore::with_config(PeerId::random(), MemoryStoreConfig { max_value_bytes: MAX_RECORD_SIZE, ..Default::default() });
+        let record = kad::Record::new(mailbox_key(owner), serde_cbor::to_vec(stored).unwrap());
+        store.put(record.clone()).unwrap();
+
+        let merged = merge_record(&store, owner, record, incoming).unwrap();
+        let merged: MailboxRecord = serde_cbor::from_slice(&merged.value).unwrap();
+        merged.messages.into_iter().map(|message| message.id).collect()
+    }
+
+
+    fn ids(messages: &[SealedMessage]) -> Vec<String> {
+        messages.iter().map(|message| message.id.clone()).collect()
+    }
+
+
+    #[test]
+    fn sealed_messages_open_for_their_recipient_only() {
+        let (sender, recipient) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let recipient_id = recipient.public().to_peer_id();
+        let message = message(&sender, &recipient_id, "hello");
+
+        let sealed = Mailbox::new(&sender).unwrap().seal(&recipient_id, &message).unwrap();
+        assert_eq!(sealed.id, message.id);
+        assert_eq!(Mailbox::new(&recipient).unwrap().open(&sealed).unwrap(), message);
+
+        assert!(Mailbox::new(&Keypair::generate_ed25519()).unwrap().open(&sealed).is_err());
+        assert!(Mailbox::new(&sender).unwrap().open(&sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(Mailbox::new(&recipient).unwrap().open(&tampered).is_err());
+    }
+
+
+    #[test]
+    fn sealed_messages_must_be_signed_by_their_sender() {
+        let (sender, recipient) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let recipient_id = recipient.public().to_peer_id();
+
+        // Signed by someone other than the peer it claims to be from
+        let forged = message(&Keypair::generate_ed25519(), &recipient_id, "hello");
+        let sealed = Mailbox::new(&sender).unwrap().seal(&recipient_id, &forged).unwrap();
+        assert!(Mailbox::new(&recipient).unwrap().open(&sealed).is_err());
+    }
+
+
+    #[test]
+    fn mailboxes_stored_at_the_same_time_are_merged() {
+        let owner = Keypair::generate_ed25519().public().to_peer_id();
+        let (first, second, third) = (sealed(&owner), sealed(&owner), sealed(&owner));
+
+        let stored = MailboxRecord { messages: vec![first.clone(), second.clone()], removed: None };
+        let incoming = MailboxRecord { messages: vec![first.clone(), third.clone()], removed: None };
+        assert_eq!(merge(&owner, &stored, incoming), ids(&[first.clone(), second.clone(), third]));
+
+        // Storing an empty mailbox doesn't empty it
+        assert_eq!(merge(&owner, &stored, MailboxRecord::default()), ids(&[first, second]));
+    }
+
+
+    #[test]
+    fn full_mailboxes_keep_what_they_have() {
+        let owner = Keypair::generate_ed25519().public().to_peer_id();
+        let stored = MailboxRecord { messages: (0..MAX_MAILBOX_MESSAGES).map(|_| sealed(&owner)).collect(), removed: None };
+        let incoming = MailboxRecord { messages: vec![sealed(&owner)], removed: None };
+
+        assert_eq!(merge(&owner, &stored, incoming), ids(&stored.messages));
+    }
+
+
+    #[test]
+    fn only_the_owner_can_remove_messages() {
+        let owner = Keypair::generate_ed25519();
+        let owner_id = owner.public().to_peer_id();
+        let (first, second) = (sealed(&owner_id), sealed(&owner_id));
+        let stored = MailboxRecord { messages: vec![first.clone(), second.clone()], removed: None };
+
+        let forged = MailboxRecord { messages: vec![], removed: Some(removal(&Keypair::generate_ed25519(), &[&first.id], 1)) };
+        assert_eq!(merge(&owner_id, &stored, forged), ids(&[first.clone(), second.clone()]));
+
+        let removed = MailboxRecord { messages: vec![], removed: Some(removal(&owner, &[&first.id], 1)) };
+        assert_eq!(merge(&owner_id, &stored, removed), vec![second.id.clone()]);
+    }
+
+
+    #[test]
+    fn removed_messages_cant_be_added_back() {
+        let owner = Keypair::generate_ed25519();
+        let owner_id = owner.public().to_peer_id();
+        let (first, second) = (sealed(&owner_id), sealed(&owner_id));
+
+        let stored = MailboxRecord { messages: vec![second.clone()], removed: Some(removal(&owner, &[&first.id], 2)) };
+
+        // A peer who read the mailbox before it was cleared stores it again, along with an older removal
+        let stale = MailboxRecord { messages: vec![first.clone(), second.clone()], removed: Some(removal(&owner, &[], 1)) };
+        assert_eq!(merge(&owner_id, &stored, stale), vec![second.id.clone()]);
+
+        // A newer removal replaces it
+        let newer = MailboxRecord { messages: vec![], removed: Some(removal(&owner, &[&second.id], 3)) };
+        assert!(merge(&owner_id, &stored, newer).is_empty());
+    }
+}
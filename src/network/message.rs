@@ -0,0 +1,102 @@
+use std::error::Error;
+use chrono::Utc;
+use libp2p::{identity::Keypair, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::util;
+
+use super::identity::public_key;
+
+/// Version of the ChatMessage format we publish. Bump when a change would stop older clients understanding messages.
+pub const CHAT_MESSAGE_VERSION: u16 = 1;
+
+
+/// A chat message as published to a room. Encoded with CBOR, so new (optional) fields can be added without breaking older
+/// clients, which ignore fields they don't know about.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub version: u16,
+    /// Random id, unique to this message.
+    pub id: String,
+    /// PeerId of the author. Must match the (signed) source of the gossipsub message carrying it.
+    pub sender: String,
+    /// The authors nickname when they sent the message.
+    pub nickname: String,
+    /// Milliseconds since the Unix epoch, according to the author.
+    pub timestamp: i64,
+    /// The authors logical clock when they sent the message, which orders messages so that a reply always comes after what
+    /// it replies to (see GlobalState::tick). Missing (0) from older clients.
+    #[serde(default)]
+    pub clock: u64,
+    pub room: String,
+    pub body: String,
+    /// The authors signature over the message (see sign), so it can still be checked once other peers pass it on (see
+    /// history_sync.rs). Missing from older clients.
+    #[serde(default)]
+    pub signature: Option<Vec<u8>>,
+}
+
+
+impl ChatMessage {
+
+    /// Creates a new message from us, stamped with a fresh id, the current time and our clock.
+    pub fn new(sender: PeerId, nickname: String, room: String, body: String, clock: u64) -> Self {
+        ChatMessage {
+            version: CHAT_MESSAGE_VERSION,
+            id: util::random_id(),
+            sender: sender.to_string(),
+            nickname,
+            timestamp: Utc::now().timestamp_millis(),
+            clock,
+            room,
+            body,
+            signature: None,
+        }
+    }
+
+
+    /// Signs the message as its author. The nickname isn't signed, as it is shown as whatever we know the author by.
+    pub fn sign(&mut self, keypair: &Keypair) -> Result<(), Box<dyn Error>> {
+        self.signature = Some(keypair.sign(&self.signed_bytes()?)?);
+        Ok(())
+    }
+
+
+    /// Checks the message was signed by the peer it claims to be from.
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+
+        let sender: PeerId = self.sender.parse()?;
+        let signature = self.signature.as_ref().ok_or_else(|| format!("Message {} isn't signed", self.id))?;
+
+        if !public_key(&sender)?.verify(&self.signed_bytes()?, signature) {
+            return Err(format!("Bad signature on message {} from {}", self.id, sender).into());
+        }
+
+        Ok(())
+    }
+
+
+    /// The parts of the message covered by its signature.
+    fn signed_bytes(&self) -> Result<Vec<u8>, serde_cbor::Error> {
+        serde_cbor::to_vec(&(&self.id, &self.sender, self.timestamp, self.clock, &self.room, &self.body))
+    }
+
+
+    /// Encodes the message to be published.
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+
+    /// Decodes a published message. Messages from newer clients are still accepted, as long as they can be read.
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+
+        let message: ChatMessage = serde_cbor::from_slice(data)?;
+
+        if message.version > CHAT_MESSAGE_VERSION {
+            log::info!("Received a version {} message (we use version {})", message.version, CHAT_MESSAGE_VERSION);
+        }
+
+        Ok(message)
+    }
+}
@@ -1,15 +1,25 @@
-use libp2p::{gossipsub, mdns, noise, request_response::{self, ProtocolSupport}, swarm::NetworkBehaviour, tcp, yamux, Multiaddr};
+use libp2p::{gossipsub, identify, identity, mdns, noise, ping, rendezvous, request_response::{self, ProtocolSupport}, swarm::NetworkBehaviour, tcp, yamux};
 use serde::{Serialize, Deserialize};
 use libp2p::StreamProtocol;
 use std::{error::Error, time::Duration};
 use futures::channel::mpsc;
 use libp2p::kad;
-use libp2p::kad::store::MemoryStore;
+use libp2p::kad::store::{MemoryStore, MemoryStoreConfig};
 use libp2p::kad::Mode;
 
-use crate::state::STATE;
+use crate::{config::Config, state::STATE};
+
+use super::{mailbox::Mailbox, message::ChatMessage, ratings::SignedReceipt, behaviour::{direct_message::DIRECT_MESSAGE_PROTOCOL, file_chunk::FILE_CHUNK_PROTOCOL, request_response::FILE_EXCHANGE_PROTOCOL, history_sync::HISTORY_SYNC_PROTOCOL, identify::{agent_version, PROTOCOL_VERSION}, ping::PING_INTERVAL, rendezvous::RendezvousPoint}, client::Client, event_loop::EventLoop};
+use libp2p::rendezvous::Namespace;
+
+/// Connections to other peers are kept open even when nothing is being sent, so that the peer list reflects who is actually
+/// online. Dead connections are still detected and closed by the transport (and by failing pings, see ping.rs).
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(u64::MAX);
+
+/// Largest record kept in the DHT. A peer's ratings are never dropped from their rating record (see ratings.rs), so it needs
+/// room to keep growing.
+pub const MAX_RECORD_SIZE: usize = 1024 * 1024;
 
-use super::{client::Client, event_loop::EventLoop};
 
 /// Main network entry point. Defines the behaviour of our libp2p application.
 #[derive(NetworkBehaviour)]
@@ -17,30 +27,93 @@ pub struct ChatBehaviour {
     pub mdns: mdns::tokio::Behaviour,
     pub gossipsub: gossipsub::Behaviour,
     pub request_response: request_response::cbor::Behaviour<Request, Response>,
-    pub kademlia: kad::Behaviour<MemoryStore>
+    pub kademlia: kad::Behaviour<MemoryStore>,
+    pub rendezvous: rendezvous::client::Behaviour,
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
+    pub history_sync: request_response::cbor::Behaviour<HistoryRequest, HistoryResponse>,
+    pub direct_message: request_response::cbor::Behaviour<ChatMessage, DirectMessageAck>,
+    pub file_chunk: request_response::cbor::Behaviour<ChunkRequest, ChunkResponse>,
 }
 
 
 /// Defines the properties sent when requesting a file from another user.
-/// Simply just a message (eg. Hey Ben, can I have last weeks COSC473 Notes)
+/// A message (eg. Hey Ben, can I have last weeks COSC473 Notes) and an id for the trade.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
     pub message: String,
+    /// Id for the trade, which the receipt sent back with the file will be for. Also identifies the transfer of the file.
+    pub trade_id: String,
 }
 
 
-/// Defines the properties sent when sharing a file with another user.
+/// Defines the properties sent when sharing a file with another user. The file itself isn't sent here: it is offered, and
+/// the other user then fetches it a chunk at a time (see transfer.rs).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Response {
     pub filename: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// SHA-256 of the file (hex), checked against what is received (see transfer.rs).
+    pub sha256: String,
+}
+
+
+/// Asks for the part of an offered file starting at the given offset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    pub trade_id: String,
+    pub offset: u64,
+    /// SHA-256 of the file as it was offered (hex), so a resumed download only carries on with the same file.
+    pub sha256: String,
+}
+
+
+/// Part of an offered file, up to CHUNK_SIZE bytes. Empty if the file (or that part of it) isn't available.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkResponse {
     pub data: Vec<u8>,
+    /// Receipt for the trade, needed to rate the peer who sent the file (see ratings.rs). Only given once every chunk of the file
+    /// has been sent.
+    pub receipt: Option<SignedReceipt>,
+}
+
+
+/// Asks another member of a room for the messages we missed, ie. those since our newest message (see history_sync.rs).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub room: String,
+    /// Timestamp of our newest message in the room (0 for none).
+    pub since: i64,
+    /// Id of our newest message in the room, if we have one.
+    pub after: Option<String>,
+}
+
+
+/// The messages of a room another peer has that we asked for, oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryResponse {
+    pub messages: Vec<ChatMessage>,
+}
+
+
+/// Confirms a DM (see direct_message.rs) was received.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessageAck {
+    pub id: String,
 }
 
 
-/// Sets up a new libp2p swarm and returns an EventLoop and Client to be used in the main program
-pub fn new() -> Result<(Client, EventLoop), Box<dyn Error>> {
+/// Sets up a new libp2p swarm and returns an EventLoop and Client to be used in the main program.
+/// The keypair is the peers persistent identity (see identity.rs), so the PeerId stays the same between sessions.
+/// Must be called after the user has entered their nickname, as it is advertised to other peers through identify.
+pub fn new(keypair: identity::Keypair, config: &Config) -> Result<(Client, EventLoop), Box<dyn Error>> {
+
+    let nickname = STATE.lock().unwrap().nickname.clone();
+    let mailbox = Mailbox::new(&keypair)?;
+    let signing_keypair = keypair.clone();
 
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -60,30 +133,80 @@ pub fn new() -> Result<(Client, EventLoop), Box<dyn Error>> {
                 )?,
                 request_response: request_response::cbor::Behaviour::new(
                     [(
-                        StreamProtocol::new("/file-exchange/1"),
+                        StreamProtocol::new(FILE_EXCHANGE_PROTOCOL),
                         ProtocolSupport::Full,
                     )],
                     request_response::Config::default().with_request_timeout(Duration::from_secs(7200)),
                 ),
-                kademlia: kad::Behaviour::new(key.public().to_peer_id(), MemoryStore::new(key.public().to_peer_id())),
+                kademlia: kad::Behaviour::with_config(
+                    key.public().to_peer_id(),
+                    MemoryStore::with_config(key.public().to_peer_id(), MemoryStoreConfig { max_value_bytes: MAX_RECORD_SIZE, ..Default::default() }),
+                    kademlia_config(),
+                ),
+                rendezvous: rendezvous::client::Behaviour::new(key.clone()),
+                identify: identify::Behaviour::new(
+                    identify::Config::new(PROTOCOL_VERSION.to_string(), key.public())
+                        .with_agent_version(agent_version(&nickname))
+                ),
+                ping: ping::Behaviour::new(ping::Config::new().with_interval(PING_INTERVAL)),
+                history_sync: request_response::cbor::Behaviour::new(
+                    [(
+                        StreamProtocol::new(HISTORY_SYNC_PROTOCOL),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                ),
+                direct_message: request_response::cbor::Behaviour::new(
+                    [(
+                        StreamProtocol::new(DIRECT_MESSAGE_PROTOCOL),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                ),
+                file_chunk: request_response::cbor::Behaviour::new(
+                    [(
+                        StreamProtocol::new(FILE_CHUNK_PROTOCOL),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                ),
             })
         })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(1)))
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT))
         .build();
 
         // Set local storage
-        let mut state = STATE.lock().unwrap();
-        state.peer_id = swarm.local_peer_id().to_string();
+        STATE.lock().unwrap().peer_id = swarm.local_peer_id().to_string();
+
+        // Addresses to listen on. A single address failing (eg. no IPv6 on this machine) shouldn't stop the others.
+        let mut listening = false;
+        for address in config.listen_multiaddrs()? {
+            match swarm.listen_on(address.clone()) {
+                Ok(_) => listening = true,
+                Err(e) => log::info!("Failed to listen on {}: {}", address, e),
+            }
+        }
 
-        // Address to listen on
-        let external_address: Multiaddr = "/ip4/0.0.0.0/udp/0/quic-v1".parse()?;
-        swarm.listen_on(external_address.clone())?;
+        if !listening {
+            return Err("Unable to listen on any of the configured addresses".into());
+        }
 
         // Network Setup
         let topic = gossipsub::IdentTopic::new("global");
         swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
         swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
 
+        // Optional rendezvous node for discovering peers beyond the local network
+        let rendezvous_point = match config.rendezvous_point()? {
+            Some((peer_id, address)) => Some(RendezvousPoint {
+                peer_id,
+                address,
+                namespace: Namespace::new(config.rendezvous_namespace.clone())?,
+                cookie: None,
+            }),
+            None => None,
+        };
+
         // Allows for cross task communication
         let (command_sender, command_receiver) = mpsc::channel(0);
 
@@ -91,6 +214,16 @@ pub fn new() -> Result<(Client, EventLoop), Box<dyn Error>> {
             Client {
                 sender: command_sender,
             },
-            EventLoop::new(swarm, command_receiver),
+            EventLoop::new(swarm, command_receiver, config.bootstrap_multiaddrs()?, rendezvous_point, mailbox, signing_keypair),
         ))
-}
\ No newline at end of file
+}
+
+/// Kademlia is set up so that records other peers ask us to store are checked before they are kept (see kademlia.rs), and
+/// so records up to MAX_RECORD_SIZE fit in a single message.
+fn kademlia_config() -> kad::Config {
+
+    let mut config = kad::Config::default();
+    config.set_record_filtering(kad::StoreInserts::FilterBoth);
+    config.set_max_packet_size(2 * MAX_RECORD_SIZE);
+    config
+}
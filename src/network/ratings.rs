@@ -0,0 +1,540 @@
+use std::{collections::HashMap, error::Error, time::{Duration, Instant}};
+use chrono::Utc;
+use libp2p::{gossipsub, identity::Keypair, kad::{self, store::{MemoryStore, RecordStore}, QueryId}, PeerId, Swarm};
+use serde::{Deserialize, Serialize};
+
+use crate::state::STATE;
+
+use super::{identity::public_key, network::ChatBehaviour};
+
+/// How long a fetched rating is shown before it is fetched again.
+const RATING_TTL: Duration = Duration::from_secs(300);
+
+/// How often ratings that have gone stale are refreshed, for the peers we are still connected to.
+pub const RATING_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Gossipsub topic on which peers announce they have changed someone's rating. It isn't a room, so it is never shown.
+pub const RATINGS_TOPIC: &str = "swapbytes-ratings";
+
+
+/// A record of a file being traded, given by the peer who sent it to the peer who asked for it once the whole file has been
+/// fetched. A peer can only be rated for a trade they have given a receipt for, and the rating is signed by the peer who got
+/// the file, for that trade and file (see RatingAttestation), so nobody else can rate them for it.
+///
+/// It is not proof the trade was between two different people though: anyone can make up new identities, trade with them,
+/// and have them rate the trades. Receipts only stop ratings being given without either side of a trade agreeing to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TradeReceipt {
+    /// Id of the trade, chosen by the peer asking for the file (see command.rs).
+    pub trade: String,
+    /// PeerId of the peer who sent the file.
+    pub provider: String,
+    /// PeerId of the peer who asked for the file.
+    pub recipient: String,
+    pub filename: String,
+    /// Size of the file in bytes.
+    #[serde(default)]
+    pub size: u64,
+    /// SHA-256 of the file (hex), so the receipt is for exactly the file that was offered.
+    #[serde(default)]
+    pub sha256: String,
+    /// Milliseconds since the Unix epoch.
+    pub issued: i64,
+}
+
+
+/// A TradeReceipt signed by the peer who sent the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedReceipt {
+    receipt: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+
+impl SignedReceipt {
+
+    /// Gives a receipt for a file we are sending.
+    pub fn new(keypair: &Keypair, trade: String, recipient: &PeerId, filename: String, size: u64, sha256: String) -> Result<SignedReceipt, Box<dyn Error>> {
+
+        let receipt = TradeReceipt {
+            trade,
+            provider: keypair.public().to_peer_id().to_string(),
+            recipient: recipient.to_string(),
+            filename,
+            size,
+            sha256,
+            issued: Utc::now().timestamp_millis(),
+        };
+
+        let receipt = serde_cbor::to_vec(&receipt)?;
+        let signature = keypair.sign(&receipt)?;
+
+        Ok(SignedReceipt { receipt, signature })
+    }
+
+
+    /// Reads the receipt, checking it was signed by the peer who sent the file.
+    pub fn verify(&self) -> Result<TradeReceipt, Box<dyn Error>> {
+
+        let receipt: TradeReceipt = serde_cbor::from_slice(&self.receipt)?;
+        let provider: PeerId = receipt.provider.parse()?;
+
+        if !public_key(&provider)?.verify(&self.receipt, &self.signature) {
+            return Err(format!("Bad signature on receipt from {}", provider).into());
+        }
+
+        Ok(receipt)
+    }
+}
+
+
+/// One peer's rating of another, for a trade between them. A peer's rating isn't stored as a number anyone could
+/// overwrite, but as the list of ratings (attestations) they have been given, each signed by whoever gave it and carrying
+/// the receipt for the trade. Readers check every signature and add them up themselves (see score).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RatingAttestation {
+    /// PeerId of the peer giving the rating.
+    pub rater: String,
+    /// PeerId of the peer being rated.
+    pub subject: String,
+    /// Id of the trade being rated. Each trade can only be rated once.
+    pub trade: String,
+    /// SHA-256 of the file traded (hex), as given in the receipt, so the rater signs for exactly the file they were sent.
+    #[serde(default)]
+    pub sha256: String,
+    /// 1 (good) or -1 (bad).
+    pub score: i32,
+    /// Milliseconds since the Unix epoch.
+    pub issued: i64,
+}
+
+
+/// A RatingAttestation as stored in the DHT, signed by the rater.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedAttestation {
+    attestation: Vec<u8>,
+    signature: Vec<u8>,
+    /// Receipt for the trade being rated. Missing from ratings given before trades had receipts, which no longer count.
+    #[serde(default)]
+    receipt: Option<SignedReceipt>,
+}
+
+
+impl SignedAttestation {
+
+    /// Rates the peer who sent us a file, for the trade in the given receipt.
+    pub fn new(keypair: &Keypair, receipt: SignedReceipt, score: i32) -> Result<SignedAttestation, Box<dyn Error>> {
+
+        let trade = receipt.verify()?;
+
+        let attestation = RatingAttestation {
+            rater: keypair.public().to_peer_id().to_string(),
+            subject: trade.provider,
+            trade: trade.trade,
+            sha256: trade.sha256,
+            score,
+            issued: Utc::now().timestamp_millis(),
+        };
+
+        let attestation = serde_cbor::to_vec(&attestation)?;
+        let signature = keypair.sign(&attestation)?;
+
+        Ok(SignedAttestation { attestation, signature, receipt: Some(receipt) })
+    }
+
+
+    /// Reads the attestation, checking it is a valid rating of the given peer signed by the peer who gave it, for a trade (and
+    /// file) the rated peer gave them a receipt for. Peers can't rate themselves, and as the subject is signed a rating of one
+    /// peer can't be passed off as a rating of another. Nothing stops a peer rating themselves through identities they made
+    /// up, though (see TradeReceipt).
+    pub fn verify(&self, subject: &PeerId) -> Result<RatingAttestation, Box<dyn Error>> {
+
+        let attestation: RatingAttestation = serde_cbor::from_slice(&self.attestation)?;
+        let rater: PeerId = attestation.rater.parse()?;
+
+        if !public_key(&rater)?.verify(&self.attestation, &self.signature) {
+            return Err(format!("Bad signature on rating from {}", rater).into());
+        }
+        if attestation.subject != subject.to_string() {
+            return Err(format!("Rating from {} is for {}, not {}", rater, attestation.subject, subject).into());
+        }
+        if rater == *subject {
+            return Err(format!("{} rated themselves", rater).into());
+        }
+        if attestation.score != 1 && attestation.score != -1 {
+            return Err(format!("Rating from {} is out of range ({})", rater, attestation.score).into());
+        }
+
+        let receipt = self.receipt.as_ref().ok_or_else(|| format!("Rating from {} has no receipt", rater))?.verify()?;
+        if receipt.provider != attestation.subject || receipt.recipient != attestation.rater || receipt.trade != attestation.trade || receipt.sha256 != attestation.sha256 {
+            return Err(format!("Rating from {} doesn't match its receipt", rater).into());
+        }
+
+        Ok(attestation)
+    }
+}
+
+
+/// The DHT key of a peers rating record.
+pub fn rating_key(peer_id: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("rating_{}", peer_id))
+}
+
+
+/// The ratings in a peers rating record that can be trusted: those that verify, and only the most recent for each trade
+/// (so the same trade can't be counted twice, and an older rating can't be replayed over a newer one). Oldest first.
+pub fn verify_all(subject: &PeerId, attestations: Vec<SignedAttestation>) -> Vec<(RatingAttestation, SignedAttestation)> {
+
+    let mut latest: HashMap<String, (RatingAttestation, SignedAttestation)> = HashMap::new();
+
+    for signed in attestations {
+        match signed.verify(subject) {
+            Ok(attestation) => {
+                if latest.get(&attestation.trade).is_none_or(|(existing, _)| existing.issued < attestation.issued) {
+                    latest.insert(attestation.trade.clone(), (attestation, signed));
+                }
+            }
+            Err(e) => log::info!("Ignoring rating of {}: {}", subject, e),
+        }
+    }
+
+    let mut verified: Vec<_> = latest.into_values().collect();
+    verified.sort_by_key(|(attestation, _)| attestation.issued);
+    verified
+}
+
+
+/// A peers rating, from the verified ratings they have been given (see verify_all).
+pub fn score(verified: &[(RatingAttestation, SignedAttestation)]) -> i32 {
+    verified.iter().map(|(attestation, _)| attestation.score).sum()
+}
+
+
+/// Adds our rating of a peer to the ratings they already have (replacing any earlier rating of the same trade), and stores
+/// it back in the DHT. Returns their new rating. The peers storing the record merge it with what they have (see merge_record),
+/// so a rating someone else stored since we read the record isn't lost.
+pub fn put_rating(swarm: &mut Swarm<ChatBehaviour>, subject: &PeerId, attestations: Vec<SignedAttestation>, ours: SignedAttestation) -> Result<i32, Box<dyn Error>> {
+
+    let attestation = ours.verify(subject)?;
+    let mut verified = verify_all(subject, attestations);
+    verified.retain(|(existing, _)| existing.trade != attestation.trade);
+    verified.push((attestation, ours));
+
+    let record = kad::Record {
+        key: rating_key(subject),
+        value: encode(&verified)?,
+        publisher: None,
+        expires: None,
+    };
+
+    swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One)?;
+    Ok(score(&verified))
+}
+
+
+/// Merges a rating record another peer has asked us to store with the one we already have, returning the record to keep.
+/// Only ratings that verify are kept, and none of the ones we have are ever dropped, so a peer can't clear the bad ratings
+/// out of their own record (by storing it with fewer ratings), and peers rating someone at the same time don't overwrite
+/// each other.
+pub fn merge_record(store: &MemoryStore, subject: &PeerId, record: kad::Record, attestations: Vec<SignedAttestation>) -> Result<kad::Record, Box<dyn Error>> {
+
+    let stored: Vec<SignedAttestation> = match store.get(&record.key) {
+        Some(stored) => serde_cbor::from_slice(&stored.value)?,
+        None => vec![],
+    };
+
+    let verified = verify_all(subject, stored.into_iter().chain(attestations).collect());
+    Ok(kad::Record { value: encode(&verified)?, ..record })
+}
+
+
+/// Encodes the verified ratings of a peer as the value of their rating record.
+fn encode(verified: &[(RatingAttestation, SignedAttestation)]) -> Result<Vec<u8>, serde_cbor::Error> {
+    let attestations: Vec<&SignedAttestation> = verified.iter().map(|(_, signed)| signed).collect();
+    serde_cbor::to_vec(&attestations)
+}
+
+
+/// Finishes rating a peer once their current ratings have been read from the DHT (see command.rs), letting everyone who
+/// has their old rating cached know it has changed.
+pub fn finish_rating(swarm: &mut Swarm<ChatBehaviour>, rating_cache: &mut RatingCache, subject: PeerId, attestations: Vec<SignedAttestation>, ours: SignedAttestation) {
+
+    match put_rating(swarm, &subject, attestations, ours) {
+        Ok(rating) => {
+            rating_cache.set(subject, Some(rating));
+            announce(swarm, &subject);
+        }
+        Err(e) => log::info!("Failed to rate {}: {}", subject, e),
+    }
+}
+
+
+/// Published on the ratings topic after rating a peer, so everyone with their rating cached fetches it again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RatingAnnouncement {
+    pub peer: String,
+}
+
+
+/// A rating fetched from the DHT. None if the peer doesn't have one.
+struct CachedRating {
+    rating: Option<i32>,
+    fetched: Instant,
+}
+
+
+impl CachedRating {
+
+    fn is_fresh(&self) -> bool {
+        self.fetched.elapsed() < RATING_TTL
+    }
+}
+
+
+/// Ratings of the peers we've heard from, so a busy room doesn't cost a DHT lookup for every message. Stale ratings are
+/// still shown while they're refreshed in the background, and a rating is dropped as soon as a change to it is announced.
+#[derive(Default)]
+pub struct RatingCache {
+    ratings: HashMap<PeerId, CachedRating>,
+    /// Ratings being fetched from the DHT.
+    fetching: HashMap<QueryId, PeerId>,
+    /// Messages (room, message id) shown before their senders rating was known, to be updated once it is.
+    waiting: HashMap<PeerId, Vec<(String, String)>>,
+}
+
+
+impl RatingCache {
+
+    /// The rating to show on a message just received from a peer. If it isn't known yet, the message is updated once it
+    /// has been fetched (see on_rating). Stale or missing ratings are fetched again.
+    pub fn lookup(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer: PeerId, room: String, id: String) -> Option<i32> {
+
+        let cached = self.ratings.get(&peer);
+        let rating = cached.and_then(|cached| cached.rating);
+
+        if rating.is_none() {
+            self.waiting.entry(peer).or_default().push((room, id));
+        }
+
+        if !cached.is_some_and(CachedRating::is_fresh) {
+            self.fetch(swarm, peer);
+        }
+
+        rating
+    }
+
+
+    /// Handles a rating fetched from the DHT (None if it couldn't be found), updating any messages waiting on it. Returns
+    /// false if the query wasn't one of ours.
+    pub fn on_rating(&mut self, query_id: &QueryId, rating: Option<i32>) -> bool {
+
+        let Some(peer) = self.fetching.remove(query_id) else {
+            return false;
+        };
+
+        self.set(peer, rating);
+        true
+    }
+
+
+    /// Caches a rating we know to be current (eg. one we have just updated ourselves).
+    pub fn set(&mut self, peer: PeerId, rating: Option<i32>) {
+
+        self.ratings.insert(peer, CachedRating { rating, fetched: Instant::now() });
+
+        let waiting = self.waiting.remove(&peer).unwrap_or_default();
+        if let Some(rating) = rating {
+            let mut state = STATE.lock().unwrap();
+            for (room, id) in waiting {
+                state.set_rating(&room, &id, rating);
+            }
+        }
+    }
+
+
+    /// Forgets a peers rating once it has changed, so the next message from them fetches it again.
+    pub fn invalidate(&mut self, peer: &PeerId) {
+        self.ratings.remove(peer);
+    }
+
+
+    /// Fetches stale ratings again for the peers we are still connected to. Called every RATING_REFRESH_INTERVAL.
+    pub fn refresh(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+
+        let stale: Vec<PeerId> = self.ratings.iter()
+            .filter(|(peer, cached)| !cached.is_fresh() && swarm.is_connected(peer))
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        for peer in stale {
+            self.fetch(swarm, peer);
+        }
+    }
+
+
+    /// Fetches a peers rating from the DHT, unless it is already being fetched.
+    fn fetch(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer: PeerId) {
+
+        if self.fetching.values().any(|fetching| *fetching == peer) {
+            return;
+        }
+
+        let query_id = swarm.behaviour_mut().kademlia.get_record(rating_key(&peer));
+        self.fetching.insert(query_id, peer);
+    }
+}
+
+
+/// Lets everyone know a peers rating has changed.
+pub fn announce(swarm: &mut Swarm<ChatBehaviour>, peer: &PeerId) {
+
+    let announcement = serde_cbor::to_vec(&RatingAnnouncement { peer: peer.to_string() }).unwrap();
+    let topic = gossipsub::IdentTopic::new(RATINGS_TOPIC);
+
+    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic, announcement) {
+        log::info!("Failed to announce rating of {}: {:?}", peer, e);
+    }
+}
+
+
+/// Handles a rating change announced by another peer.
+pub fn on_announcement(rating_cache: &mut RatingCache, data: &[u8]) {
+
+    let peer = serde_cbor::from_slice::<RatingAnnouncement>(data).map_err(|e| e.to_string())
+        .and_then(|announcement| announcement.peer.parse::<PeerId>().map_err(|e| e.to_string()));
+
+    match peer {
+        Ok(peer) => {
+            log::info!("Rating of {} has changed", peer);
+            rating_cache.invalidate(&peer);
+        }
+        Err(e) => log::info!("Dropping unreadable rating announcement: {}", e),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A receipt for a trade of a file from the provider to the recipient.
+    fn receipt(provider: &Keypair, recipient: &Keypair, trade: &str) -> SignedReceipt {
+        SignedReceipt::new(provider, trade.to_string(), &recipient.public().to_peer_id(), "notes.txt".to_string(), 10, "00".repeat(32)).unwrap()
+    }
+
+
+    /// A rating given at the given time, for the trade in the receipt.
+    fn rate(rater: &Keypair, receipt: SignedReceipt, score: i32, issued: i64) -> SignedAttestation {
+
+        let trade = receipt.verify().unwrap();
+        let attestation = RatingAttestation {
+            rater: rater.public().to_peer_id().to_string(),
+            subject: trade.provider,
+            trade: trade.trade,
+            sha256: trade.sha256,
+            score,
+            issued,
+        };
+
+        let attestation = serde_cbor::to_vec(&attestation).unwrap();
+        let signature = rater.sign(&attestation).unwrap();
+        SignedAttestation { attestation, signature, receipt: Some(receipt) }
+    }
+
+
+    fn scores(verified: &[(RatingAttestation, SignedAttestation)]) -> Vec<i32> {
+        verified.iter().map(|(attestation, _)| attestation.score).collect()
+    }
+
+
+    #[test]
+    fn ratings_are_given_for_a_receipt() {
+        let (provider, recipient) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let signed = SignedAttestation::new(&recipient, receipt(&provider, &recipient, "trade"), -1).unwrap();
+
+        let attestation = signed.verify(&provider.public().to_peer_id()).unwrap();
+        assert_eq!(attestation.trade, "trade");
+        assert_eq!(attestation.score, -1);
+        assert!(signed.verify(&recipient.public().to_peer_id()).is_err());
+    }
+
+
+    #[test]
+    fn only_the_latest_rating_of_each_trade_counts() {
+        let (provider, recipient) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let subject = provider.public().to_peer_id();
+
+        let ratings = vec![
+            rate(&recipient, receipt(&provider, &recipient, "first"), 1, 3),
+            rate(&recipient, receipt(&provider, &recipient, "first"), -1, 1),
+            rate(&recipient, receipt(&provider, &recipient, "second"), -1, 2),
+        ];
+
+        let verified = verify_all(&subject, ratings);
+        assert_eq!(scores(&verified), vec![-1, 1]);
+        assert_eq!(score(&verified), 0);
+    }
+
+
+    #[test]
+    fn ratings_that_dont_verify_are_dropped() {
+        let (provider, recipient) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let subject = provider.public().to_peer_id();
+
+        // Rating themselves
+        let own = rate(&provider, receipt(&provider, &provider, "own"), 1, 1);
+
+        // Someone else's receipt
+        let stolen = rate(&Keypair::generate_ed25519(), receipt(&provider, &recipient, "stolen"), 1, 1);
+
+        // Changed after it was signed
+        let mut tampered = rate(&recipient, receipt(&provider, &recipient, "tampered"), -1, 1);
+        let mut attestation: RatingAttestation = serde_cbor::from_slice(&tampered.attestation).unwrap();
+        attestation.score = 1;
+        tampered.attestation = serde_cbor::to_vec(&attestation).unwrap();
+
+        // No receipt, or a receipt for a different trade
+        let mut missing = rate(&recipient, receipt(&provider, &recipient, "missing"), 1, 1);
+        missing.receipt = None;
+        let mut mismatched = rate(&recipient, receipt(&provider, &recipient, "mismatched"), 1, 1);
+        mismatched.receipt = Some(receipt(&provider, &recipient, "other"));
+
+        // Out of range
+        let out_of_range = rate(&recipient, receipt(&provider, &recipient, "range"), 5, 1);
+
+        let valid = rate(&recipient, receipt(&provider, &recipient, "valid"), 1, 1);
+
+        let verified = verify_all(&subject, vec![own, stolen, tampered, missing, mismatched, out_of_range, valid]);
+        assert_eq!(verified.len(), 1);
+        assert_eq!(verified[0].0.trade, "valid");
+    }
+
+
+    #[test]
+    fn merging_never_drops_stored_ratings() {
+        let (provider, first, second) = (Keypair::generate_ed25519(), Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let subject = provider.public().to_peer_id();
+        let decode = |record: &kad::Record| verify_all(&subject, serde_cbor::from_slice(&record.value).unwrap());
+
+        let bad = rate(&first, receipt(&provider, &first, "first"), -1, 1);
+        let mut store = MemoryStore::new(PeerId::random());
+        let record = kad::Record::new(rating_key(&subject), serde_cbor::to_vec(&vec![bad]).unwrap());
+        store.put(record.clone()).unwrap();
+
+        // Storing the record without the bad rating doesn't get rid of it
+        let good = rate(&second, receipt(&provider, &second, "second"), 1, 2);
+        let merged = merge_record(&store, &subject, record.clone(), vec![good.clone()]).unwrap();
+        assert_eq!(scores(&decode(&merged)), vec![-1, 1]);
+
+        // Neither does replaying an older rating of the same trade
+        store.put(merged.clone()).unwrap();
+        let older = rate(&first, receipt(&provider, &first, "first"), 1, 0);
+        let merged = merge_record(&store, &subject, record.clone(), vec![older]).unwrap();
+        assert_eq!(scores(&decode(&merged)), vec![-1, 1]);
+
+        // But a newer rating of the same trade replaces it
+        let newer = rate(&first, receipt(&provider, &first, "first"), 1, 3);
+        let merged = merge_record(&store, &subject, record, vec![newer]).unwrap();
+        assert_eq!(scores(&decode(&merged)), vec![1, 1]);
+    }
+}
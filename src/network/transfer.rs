@@ -0,0 +1,1203 @@
+use std::{collections::{BTreeSet, HashMap}, error::Error, fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, time::{Duration, Instant}};
+use chrono::{Local, Utc};
+use libp2p::{identity::Keypair, request_response::{InboundRequestId, OutboundFailure, OutboundRequestId}, PeerId, Swarm};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{events::{self, AppEvent}, state::{Trade, TransferKind, TransferState, TransferStatus, STATE}, util};
+
+use super::{network::{ChatBehaviour, ChunkRequest, ChunkResponse, Response}, ratings::SignedReceipt};
+
+/// Most bytes sent in a single chunk.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Most chunks of a download asked for at once. The sender only ever sends what has been asked for, so this bounds how
+/// much of a file can be in flight (and in memory) at a time.
+const MAX_CHUNKS_IN_FLIGHT: u64 = 4;
+
+/// Most downloads fetched at once. Any others are queued until one of these is over (or paused).
+const MAX_ACTIVE_DOWNLOADS: usize = 3;
+
+/// Most times in a row a chunk is asked for again while still connected to the sender. After that (or once they disconnect)
+/// the download waits for them to connect again.
+const MAX_RETRIES: u32 = 3;
+
+/// How often the speed of a transfer is worked out.
+const SPEED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the directory (inside the data directory) holding downloads in progress and the files we have offered, so
+/// transfers can pick up where they left off after a disconnect or a restart.
+const TRANSFERS_DIR: &str = "transfers";
+
+/// Name of the file (inside TRANSFERS_DIR) listing the files we have offered.
+const OFFERS_FILE: &str = "offers.json";
+
+/// How long a file we offered can be fetched (or resumed) for, in milliseconds.
+const OFFER_TTL: i64 = 24 * 60 * 60 * 1000;
+
+/// Name of the log of every file sent and received (inside the data directory).
+const TRANSFER_LOG: &str = "transfers.log";
+
+
+/// A file we have offered to another peer. Kept on disk so they can still fetch it after we restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offer {
+    /// PeerId of the peer the file was offered to.
+    peer: String,
+    path: String,
+    filename: String,
+    pub size: u64,
+    /// SHA-256 of the file (hex).
+    pub sha256: String,
+    /// Milliseconds since the Unix epoch.
+    offered: i64,
+    /// Whether the last chunk has been sent.
+    finished: bool,
+}
+
+
+/// A file we have offered, waiting for the peer it was offered to to fetch it.
+struct Upload {
+    peer: PeerId,
+    offer: Offer,
+    /// Opened once the file is first fetched after a restart (and checked to be unchanged).
+    file: Option<File>,
+    state: TransferState,
+    /// How far into the file they have fetched, counting only chunks we have actually sent.
+    sent: u64,
+    /// Offsets of the chunks sent since the file was offered (or since we restarted).
+    served: BTreeSet<u64>,
+    /// Where they started fetching from. Zero for a new offer, or where they carried on from after we restarted.
+    start: Option<u64>,
+    speed: Speed,
+}
+
+
+/// A file we have asked another peer for, waiting for them to offer it.
+struct FileRequest {
+    peer: PeerId,
+    message: String,
+    state: TransferState,
+}
+
+
+/// How far a download has got. Kept on disk next to the partial file, so the download can be resumed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedDownload {
+    /// PeerId of the peer the file is fetched from.
+    peer: String,
+    /// Name to save the file under (already sanitized, see util::sanitize_filename).
+    filename: String,
+    size: u64,
+    /// SHA-256 of the file they offered (hex). The sender checks it as each chunk is asked for, and we check it once the
+    /// file is complete.
+    sha256: String,
+    /// Every byte before this has been written to the partial file.
+    offset: u64,
+    /// Receipt for the trade (if it checked out), sent with the last chunk, to rate the sender with once the file is complete.
+    receipt: Option<SignedReceipt>,
+    /// Whether the download has failed, so it only carries on if the user retries it.
+    #[serde(default)]
+    failed: bool,
+}
+
+
+/// A file being fetched from another peer, written to a partial file as each chunk arrives.
+struct Download {
+    peer: PeerId,
+    saved: SavedDownload,
+    file: File,
+    state: TransferState,
+    /// When the download was (last) queued, so queued downloads are started in order.
+    queued_at: Instant,
+    /// Chunks written past saved.offset, as they can arrive out of order.
+    ahead: BTreeSet<u64>,
+    /// Offset of the next chunk to ask for.
+    next_offset: u64,
+    in_flight: u64,
+    /// Chunks that couldn't be fetched since the last one that could.
+    failures: u32,
+    speed: Speed,
+}
+
+
+/// How fast a transfer is going, worked out about once every SPEED_INTERVAL.
+struct Speed {
+    since: Instant,
+    /// Bytes done as of `since`.
+    bytes: u64,
+    /// Bytes per second.
+    rate: u64,
+}
+
+
+impl Speed {
+
+    fn new(bytes: u64) -> Speed {
+        Speed { since: Instant::now(), bytes, rate: 0 }
+    }
+
+
+    /// Updates the speed with how many bytes are now done.
+    fn update(&mut self, bytes: u64) {
+        let elapsed = self.since.elapsed();
+        if elapsed >= SPEED_INTERVAL {
+            let rate = (bytes.saturating_sub(self.bytes) as f64 / elapsed.as_secs_f64()) as u64;
+            *self = Speed { since: Instant::now(), bytes, rate };
+        }
+    }
+}
+
+
+/// The files being sent to and fetched from other peers. Files are fetched in chunks, only as fast as the receiver asks for
+/// them, so neither side ever holds more than a few chunks of a file in memory.
+///
+/// Both sides keep their transfers in the data directory. A download cut off partway (eg. the sender went offline, or
+/// either of us restarted) asks for only what it is missing once the sender is connected again.
+///
+/// Every transfer is listed in the GlobalState, for the Transfers list on the "File Sharing" tab (where they can be
+/// cancelled and retried).
+#[derive(Default)]
+pub struct Transfers {
+    uploads: HashMap<String, Upload>,
+    downloads: HashMap<String, Download>,
+    requests: HashMap<String, FileRequest>,
+    /// Chunks asked for (trade id, offset), waiting on the sender.
+    chunk_queue: HashMap<OutboundRequestId, (String, u64)>,
+    /// Trade ids of files we have offered, waiting for the offer to reach the peer who asked for it.
+    offer_queue: HashMap<InboundRequestId, String>,
+}
+
+
+impl Transfers {
+
+    /// Reads the transfers left from the last time we ran. Downloads carry on once their sender connects (see resume).
+    pub fn load() -> Transfers {
+
+        let mut transfers = Transfers::default();
+        let dir = transfers_dir();
+
+        let offers: HashMap<String, Offer> = match fs::read(dir.join(OFFERS_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                log::info!("Failed to read {}: {}", OFFERS_FILE, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        for (trade_id, offer) in offers {
+            if let Ok(peer) = offer.peer.parse() {
+                let state = if offer.finished { TransferState::Done } else { TransferState::Queued };
+                let sent = if offer.finished { offer.size } else { 0 };
+                transfers.uploads.insert(trade_id, Upload { peer, offer, file: None, state, sent, served: BTreeSet::new(), start: None, speed: Speed::new(sent) });
+            }
+        }
+        transfers.expire_offers();
+
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for path in entries.flatten().map(|entry| entry.path()) {
+
+                if path.extension().is_none_or(|extension| extension != "json") || path.ends_with(OFFERS_FILE) {
+                    continue;
+                }
+                let Some(trade_id) = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string) else {
+                    continue;
+                };
+
+                match load_download(&trade_id) {
+                    Ok(download) => {
+                        log::info!("Found download {} of {} from {} at {} of {} bytes", trade_id, download.saved.filename, download.peer, download.saved.offset, download.saved.size);
+                        transfers.downloads.insert(trade_id, download);
+                    }
+                    Err(e) => {
+                        log::info!("Dropping download {}: {}", trade_id, e);
+                        remove_download_files(&trade_id);
+                    }
+                }
+            }
+        }
+
+        for trade_id in transfers.uploads.keys() {
+            transfers.publish_upload(trade_id);
+        }
+        for trade_id in transfers.downloads.keys() {
+            transfers.publish_download(trade_id);
+        }
+
+        transfers
+    }
+
+
+    /// Keeps track of a file we have just asked for, until it is offered to us (see start_download).
+    pub fn add_request(&mut self, trade_id: String, peer: PeerId, message: String) {
+        self.requests.insert(trade_id.clone(), FileRequest { peer, message, state: TransferState::Requested });
+        self.publish_request(&trade_id);
+    }
+
+
+    /// Whether we are still waiting on the file with the given trade id (ie. it hasn't been cancelled).
+    pub fn is_requested(&self, trade_id: &str) -> bool {
+        self.requests.get(trade_id).is_some_and(|request| request.state == TransferState::Requested)
+    }
+
+
+    /// Handles a file request that never got an answer (eg. the peer went offline).
+    pub fn request_failed(&mut self, trade_id: &str, error: &OutboundFailure) {
+
+        let Some(request) = self.requests.get_mut(trade_id).filter(|request| request.state == TransferState::Requested) else {
+            return;
+        };
+
+        request.state = TransferState::Failed;
+        let peer = request.peer;
+        self.publish_request(trade_id);
+
+        let mut state = STATE.lock().unwrap();
+        let nickname = state.nickname_or_id(&peer);
+        let room = state.current_room.clone();
+        state.push_system(&room, format!("⚠️ Couldn't get an answer from {} to your file request: {}", nickname, error));
+    }
+
+
+    /// Makes a file available for the peer it was offered to to fetch (see read_chunk). It is queued until they start fetching it.
+    pub fn add_upload(&mut self, trade_id: String, request_id: InboundRequestId, peer: PeerId, file: File, offer: Offer) {
+        self.uploads.insert(trade_id.clone(), Upload { peer, offer, file: Some(file), state: TransferState::Queued, sent: 0, served: BTreeSet::new(), start: Some(0), speed: Speed::new(0) });
+        self.offer_queue.insert(request_id, trade_id.clone());
+        self.publish_upload(&trade_id);
+        self.expire_offers();
+        self.save_offers();
+    }
+
+
+    /// Forgets a file we offered that never reached the peer it was offered to.
+    pub fn remove_upload(&mut self, trade_id: &str) {
+        self.set_upload_state(trade_id, TransferState::Failed);
+        self.offer_queue.retain(|_, queued| queued != trade_id);
+        self.uploads.remove(trade_id);
+        self.save_offers();
+    }
+
+
+    /// Handles an offer that has reached the peer who asked for the file.
+    pub fn on_offer_sent(&mut self, request_id: &InboundRequestId) {
+        self.offer_queue.remove(request_id);
+    }
+
+
+    /// Handles an offer that couldn't be sent (eg. the peer who asked for the file went offline). Returns whether it was a
+    /// file offer.
+    pub fn on_offer_failed(&mut self, request_id: &InboundRequestId) -> bool {
+
+        let Some(trade_id) = self.offer_queue.remove(request_id) else {
+            return false;
+        };
+        let Some(upload) = self.uploads.get(&trade_id) else {
+            return true;
+        };
+
+        let mut state = STATE.lock().unwrap();
+        let nickname = state.nickname_or_id(&upload.peer);
+        let room = state.current_room.clone();
+        state.push_system(&room, format!("⚠️ Couldn't offer {} to {}", upload.offer.filename, nickname));
+        drop(state);
+
+        self.remove_upload(&trade_id);
+        true
+    }
+
+
+    /// Reads the chunk of an offered file a peer has asked for. Peers can only fetch files offered to them, and only while
+    /// the file is still the one offered. Once every chunk has been sent, each chunk comes with a receipt for the trade so
+    /// the peer can rate us.
+    pub fn read_chunk(&mut self, keypair: &Keypair, peer: &PeerId, request: &ChunkRequest) -> ChunkResponse {
+
+        let Some(upload) = self.uploads.get_mut(&request.trade_id)
+            .filter(|upload| upload.peer == *peer && upload.offer.sha256 == request.sha256)
+            .filter(|upload| matches!(upload.state, TransferState::Queued | TransferState::Active | TransferState::Done)) else {
+            log::info!("{} asked for a file that wasn't offered to them", peer);
+            return ChunkResponse { data: vec![], receipt: None };
+        };
+
+        // Chunks only ever start every CHUNK_SIZE bytes, up to the end of the file
+        if request.offset >= upload.offer.size || !request.offset.is_multiple_of(CHUNK_SIZE) {
+            log::info!("{} asked for a chunk at {} of {}, which is {} bytes", peer, request.offset, upload.offer.filename, upload.offer.size);
+            return ChunkResponse { data: vec![], receipt: None };
+        }
+
+        // Not opened since we restarted, so it may have changed since it was offered
+        if upload.file.is_none() {
+            match reopen(&upload.offer) {
+                Ok(file) => upload.file = Some(file),
+                Err(e) => {
+                    log::info!("Can't send {} to {} anymore: {}", upload.offer.path, peer, e);
+                    self.set_upload_state(&request.trade_id, TransferState::Failed);
+                    return ChunkResponse { data: vec![], receipt: None };
+                }
+            }
+        }
+        let Some(file) = upload.file.as_mut() else {
+            return ChunkResponse { data: vec![], receipt: None };
+        };
+
+        let mut data = vec![];
+        let read = file.seek(SeekFrom::Start(request.offset)).and_then(|_| file.take(CHUNK_SIZE).read_to_end(&mut data));
+
+        if let Err(e) = read {
+            log::info!("Failed to read chunk at {} for {}: {}", request.offset, peer, e);
+            return ChunkResponse { data: vec![], receipt: None };
+        }
+        if data.len() as u64 != chunk_length(upload.offer.size, request.offset) {
+            log::info!("Read {} bytes at {} of {}, it is shorter than when it was offered", data.len(), request.offset, upload.offer.path);
+            return ChunkResponse { data: vec![], receipt: None };
+        }
+
+        if upload.state == TransferState::Queued {
+            upload.state = TransferState::Active;
+            upload.speed = Speed::new(upload.sent);
+        }
+
+        // Chunks asked for again (eg. after one was lost) are only counted once
+        let start = *upload.start.get_or_insert(request.offset);
+        if request.offset >= start && upload.served.insert(request.offset) {
+            upload.sent = upload.sent.max(start) + data.len() as u64;
+        }
+        upload.speed.update(upload.sent);
+
+        // The offer is kept (until it expires) after the last chunk is sent, in case it never made it to them
+        let finished = upload.sent == upload.offer.size && !upload.offer.finished;
+        if finished {
+            upload.offer.finished = true;
+            upload.state = TransferState::Done;
+            log::info!("Finished sending {} to {}", request.trade_id, peer);
+            log_transfer(&format!("[sent] {} ({} bytes) to {} sha256 {}", upload.offer.filename, upload.offer.size, peer, upload.offer.sha256));
+        }
+
+        // Only once the whole file has been sent is there a trade to give a receipt for
+        let receipt = upload.offer.finished.then(|| {
+            SignedReceipt::new(keypair, request.trade_id.clone(), peer, upload.offer.filename.clone(), upload.offer.size, upload.offer.sha256.clone())
+                .map_err(|e| log::info!("Failed to sign receipt for {}: {}", peer, e))
+                .ok()
+        }).flatten();
+
+        if finished {
+            self.save_offers();
+        }
+        self.publish_upload(&request.trade_id);
+        ChunkResponse { data, receipt }
+    }
+
+
+    /// Starts fetching a file another peer has offered us, in response to our request (see request_response.rs). The name they gave
+    /// it can't be trusted, so it is sanitized before anything is saved under it.
+    ///
+    /// The download is queued, and only fetched once fewer than MAX_ACTIVE_DOWNLOADS are (see start_queued).
+    pub fn start_download(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer: PeerId, trade_id: String, offer: Response) {
+
+        self.requests.remove(&trade_id);
+
+        let filename = util::sanitize_filename(&offer.filename);
+        let partial = partial_path(&trade_id);
+
+        let file = match fs::create_dir_all(transfers_dir()).and_then(|_| File::create(&partial)) {
+            Ok(file) => file,
+            Err(e) => {
+                log::info!("Failed to create {}: {}", partial.display(), e);
+                STATE.lock().unwrap().transfers.retain(|status| status.trade_id != trade_id);
+                return failed(&peer, &filename);
+            }
+        };
+
+        log::info!("Fetching {} ({} bytes) from {}", filename, offer.size, peer);
+        let saved = SavedDownload { peer: peer.to_string(), filename, size: offer.size, sha256: offer.sha256, offset: 0, receipt: None, failed: false };
+
+        if let Err(e) = save_download(&trade_id, &saved) {
+            log::info!("Failed to save download {}, it won't be resumable: {}", trade_id, e);
+        }
+
+        let download = Download {
+            peer,
+            saved,
+            file,
+            state: TransferState::Queued,
+            queued_at: Instant::now(),
+            ahead: BTreeSet::new(),
+            next_offset: 0,
+            in_flight: 0,
+            failures: 0,
+            speed: Speed::new(0),
+        };
+
+        self.downloads.insert(trade_id.clone(), download);
+        self.publish_download(&trade_id);
+        self.start_queued(swarm);
+    }
+
+
+    /// Writes a chunk that has arrived to its file, and asks for the next one. Finishes the download once every chunk is in.
+    pub fn on_chunk(&mut self, swarm: &mut Swarm<ChatBehaviour>, request_id: &OutboundRequestId, response: ChunkResponse) {
+
+        let Some((trade_id, offset)) = self.chunk_queue.remove(request_id) else {
+            return;
+        };
+        let Some(download) = self.downloads.get_mut(&trade_id) else {
+            return;
+        };
+
+        download.in_flight -= 1;
+        download.failures = 0;
+
+        // Each chunk is the rest of the file, up to CHUNK_SIZE. Anything else means the sender can't give us the file.
+        let expected = chunk_length(download.saved.size, offset);
+        if response.data.len() as u64 != expected {
+            log::info!("Got {} bytes at {} of {}, expected {}", response.data.len(), offset, download.saved.filename, expected);
+            return self.fail(swarm, &trade_id);
+        }
+
+        // The sender gives a receipt with the last chunk they send, which we rate them with once the file is complete
+        if let Some(signed) = response.receipt.filter(|_| download.saved.receipt.is_none()) {
+            match check_receipt(&signed, &trade_id, &download.saved, &download.peer, swarm.local_peer_id()) {
+                Ok(()) => download.saved.receipt = Some(signed),
+                Err(e) => log::info!("Ignoring receipt from {} for {}: {}", download.peer, download.saved.filename, e),
+            }
+        }
+
+        let written = download.file.seek(SeekFrom::Start(offset)).and_then(|_| download.file.write_all(&response.data));
+        if let Err(e) = written {
+            log::info!("Failed to write {}: {}", partial_path(&trade_id).display(), e);
+            return self.fail(swarm, &trade_id);
+        }
+
+        // Only what has been written without any gaps counts towards where the download would resume from
+        let resume_from = download.saved.offset;
+        advance(&mut download.saved, &mut download.ahead, offset);
+        if download.saved.offset != resume_from {
+            if let Err(e) = save_download(&trade_id, &download.saved) {
+                log::info!("Failed to save progress of download {}: {}", trade_id, e);
+            }
+        }
+        download.speed.update(download.saved.offset);
+
+        if download.saved.offset == download.saved.size {
+            self.finish(swarm, &trade_id);
+        } else {
+            self.publish_download(&trade_id);
+            self.request_chunks(swarm, &trade_id);
+        }
+    }
+
+
+    /// Handles a chunk that couldn't be fetched. The download carries on from the first byte we don't have, straight away if
+    /// we are still connected to the sender, otherwise once they connect again (see resume).
+    pub fn on_chunk_failure(&mut self, swarm: &mut Swarm<ChatBehaviour>, request_id: &OutboundRequestId) {
+
+        let Some((trade_id, _)) = self.chunk_queue.remove(request_id) else {
+            return;
+        };
+        let Some(download) = self.downloads.get_mut(&trade_id) else {
+            return;
+        };
+
+        // Chunks still on their way are asked for again, so the download only ever carries on from one place
+        self.chunk_queue.retain(|_, (queued, _)| *queued != trade_id);
+        download.ahead.clear();
+        download.next_offset = download.saved.offset;
+        download.in_flight = 0;
+        download.failures += 1;
+
+        if download.failures <= MAX_RETRIES && swarm.is_connected(&download.peer) {
+            return self.request_chunks(swarm, &trade_id);
+        }
+
+        log::info!("Pausing download {} at {} of {} bytes", trade_id, download.saved.offset, download.saved.size);
+        download.state = TransferState::Paused;
+
+        let mut state = STATE.lock().unwrap();
+        let nickname = state.nickname_or_id(&download.peer);
+        let room = state.current_room.clone();
+        state.push_system(&room, format!("⏸️ Lost {}, {} will carry on downloading when they are back", nickname, download.saved.filename));
+        drop(state);
+
+        self.publish_download(&trade_id);
+        self.start_queued(swarm);
+    }
+
+
+    /// Carries on with any paused downloads from a peer that has just connected.
+    pub fn resume(&mut self, swarm: &mut Swarm<ChatBehaviour>, peer: &PeerId) {
+
+        let paused: Vec<String> = self.downloads.iter()
+            .filter(|(_, download)| download.peer == *peer && download.state == TransferState::Paused)
+            .map(|(trade_id, _)| trade_id.clone())
+            .collect();
+
+        for trade_id in paused {
+            if let Some(download) = self.downloads.get_mut(&trade_id) {
+                log::info!("Resuming download {} from {} at {} of {} bytes", trade_id, peer, download.saved.offset, download.saved.size);
+                download.state = TransferState::Queued;
+                download.queued_at = Instant::now();
+                download.failures = 0;
+            }
+            self.publish_download(&trade_id);
+        }
+
+        self.start_queued(swarm);
+    }
+
+
+    /// Cancels a transfer. A cancelled download (or request) stays in the Transfers list so it can be retried, cancelling
+    /// it again clears it from the list. Transfers that are over are cleared straight away.
+    pub fn cancel(&mut self, swarm: &mut Swarm<ChatBehaviour>, trade_id: &str, kind: TransferKind) {
+
+        match kind {
+
+            TransferKind::Download if self.downloads.get(trade_id).is_some_and(|download| !matches!(download.state, TransferState::Cancelled | TransferState::Done)) => {
+                log::info!("Cancelling download {}", trade_id);
+                self.chunk_queue.retain(|_, (queued, _)| queued != trade_id);
+                remove_download_files(trade_id);
+
+                if let Some(download) = self.downloads.get_mut(trade_id) {
+                    download.state = TransferState::Cancelled;
+                    download.in_flight = 0;
+                }
+                self.publish_download(trade_id);
+                self.start_queued(swarm);
+            }
+
+            TransferKind::Download if self.requests.get(trade_id).is_some_and(|request| request.state != TransferState::Cancelled) => {
+                if let Some(request) = self.requests.get_mut(trade_id) {
+                    request.state = TransferState::Cancelled;
+                }
+                self.publish_request(trade_id);
+            }
+
+            // The peer fetching it is refused any more chunks (see read_chunk)
+            TransferKind::Upload if self.uploads.get(trade_id).is_some_and(|upload| upload.state != TransferState::Done) => {
+                log::info!("Cancelling upload {}", trade_id);
+                self.set_upload_state(trade_id, TransferState::Cancelled);
+                self.offer_queue.retain(|_, queued| queued != trade_id);
+                self.uploads.remove(trade_id);
+                self.save_offers();
+            }
+
+            _ => {
+                if kind == TransferKind::Download {
+                    self.downloads.remove(trade_id);
+                    self.requests.remove(trade_id);
+                }
+                STATE.lock().unwrap().transfers.retain(|status| status.trade_id != trade_id || status.kind != kind);
+            }
+        }
+    }
+
+
+    /// Retries a download that failed or was cancelled, carrying on from where it got to if we still have what it had fetched.
+    /// A file request that failed (or was cancelled) is returned (as the peer and message) to be sent again. Uploads can
+    /// only be retried if the file couldn't be read, as it is up to the peer fetching it to carry on.
+    pub fn retry(&mut self, swarm: &mut Swarm<ChatBehaviour>, trade_id: &str, kind: TransferKind) -> Option<(PeerId, String)> {
+
+        match kind {
+
+            TransferKind::Download => {
+
+                if self.requests.get(trade_id).is_some_and(|request| request.state != TransferState::Requested) {
+                    STATE.lock().unwrap().transfers.retain(|status| status.trade_id != trade_id || status.kind != kind);
+                    return self.requests.remove(trade_id).map(|request| (request.peer, request.message));
+                }
+
+                let download = self.downloads.get_mut(trade_id).filter(|download| matches!(download.state, TransferState::Failed | TransferState::Cancelled))?;
+
+                // Cancelling a download removes what it had fetched, so it starts again
+                if download.state == TransferState::Cancelled {
+                    let partial = partial_path(trade_id);
+                    match fs::create_dir_all(transfers_dir()).and_then(|_| File::create(&partial)) {
+                        Ok(file) => download.file = file,
+                        Err(e) => {
+                            log::info!("Failed to create {}: {}", partial.display(), e);
+                            return None;
+                        }
+                    }
+                    download.saved.offset = 0;
+                }
+
+                log::info!("Retrying download {} from {} bytes", trade_id, download.saved.offset);
+                download.saved.failed = false;
+                download.state = TransferState::Queued;
+                download.queued_at = Instant::now();
+                download.ahead.clear();
+                download.next_offset = download.saved.offset;
+                download.failures = 0;
+
+                if let Err(e) = save_download(trade_id, &download.saved) {
+                    log::info!("Failed to save download {}: {}", trade_id, e);
+                }
+
+                self.publish_download(trade_id);
+                self.start_queued(swarm);
+            }
+
+            TransferKind::Upload => {
+                let upload = self.uploads.get_mut(trade_id).filter(|upload| upload.state == TransferState::Failed)?;
+                upload.state = TransferState::Queued;
+                upload.file = None;
+                self.publish_upload(trade_id);
+            }
+        }
+
+        None
+    }
+
+
+    /// Starts queued downloads (oldest first) while fewer than MAX_ACTIVE_DOWNLOADS are being fetched.
+    fn start_queued(&mut self, swarm: &mut Swarm<ChatBehaviour>) {
+
+        while self.downloads.values().filter(|download| download.state == TransferState::Active).count() < MAX_ACTIVE_DOWNLOADS {
+
+            let Some(trade_id) = self.downloads.iter()
+                .filter(|(_, download)| download.state == TransferState::Queued)
+                .min_by_key(|(_, download)| download.queued_at)
+                .map(|(trade_id, _)| trade_id.clone()) else {
+                return;
+            };
+
+            if let Some(download) = self.downloads.get_mut(&trade_id) {
+                download.state = TransferState::Active;
+                download.speed = Speed::new(download.saved.offset);
+            }
+            self.publish_download(&trade_id);
+            self.request_chunks(swarm, &trade_id);
+        }
+    }
+
+
+    /// Asks for the next chunks of an active download, keeping up to MAX_CHUNKS_IN_FLIGHT asked for at a time.
+    fn request_chunks(&mut self, swarm: &mut Swarm<ChatBehaviour>, trade_id: &str) {
+
+        let Some(download) = self.downloads.get_mut(trade_id).filter(|download| download.state == TransferState::Active) else {
+            return;
+        };
+
+        // Nothing (left) to fetch
+        if download.saved.offset == download.saved.size {
+            return self.finish(swarm, trade_id);
+        }
+
+        while download.in_flight < MAX_CHUNKS_IN_FLIGHT && download.next_offset < download.saved.size {
+            let request = ChunkRequest { trade_id: trade_id.to_string(), offset: download.next_offset, sha256: download.saved.sha256.clone() };
+            let request_id = swarm.behaviour_mut().file_chunk.send_request(&download.peer, request);
+
+            self.chunk_queue.insert(request_id, (trade_id.to_string(), download.next_offset));
+            download.next_offset += CHUNK_SIZE;
+            download.in_flight += 1;
+        }
+    }
+
+
+    /// Moves a completed download into place, and lets the user rate the sender. Files already in the downloads directory are never
+    /// replaced, the new file is given a numbered name instead (eg. "notes (1).pdf").
+    ///
+    /// The file is checked against the size and hash it was offered with. One that doesn't match is still kept, but the user is
+    /// told before they rate the sender (see rating.rs).
+    fn finish(&mut self, swarm: &mut Swarm<ChatBehaviour>, trade_id: &str) {
+
+        let Some(download) = self.downloads.get(trade_id) else {
+            return;
+        };
+
+        let partial = partial_path(trade_id);
+        let hashed = download.file.sync_all().and_then(|_| File::open(&partial)).and_then(|mut file| hash(&mut file));
+        let (size, sha256) = match hashed {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                log::info!("Failed to read {}: {}", partial.display(), e);
+                return self.fail(swarm, trade_id);
+            }
+        };
+
+        let path = util::unique_path(&util::downloads_dir(), &download.saved.filename);
+        if let Err(e) = fs::create_dir_all(util::downloads_dir()).and_then(|_| move_file(&partial, &path)) {
+            log::info!("Failed to save {}: {}", path.display(), e);
+            return self.fail(swarm, trade_id);
+        }
+        remove_download_files(trade_id);
+
+        let Some(download) = self.downloads.get_mut(trade_id) else {
+            return;
+        };
+
+        let filename = util::file_name(&path.to_string_lossy());
+        let verified = size == download.saved.size && sha256 == download.saved.sha256;
+        log::info!("File {} received and saved successfully (verified: {})", path.display(), verified);
+
+        let result = if verified { "verified".to_string() } else { format!("MISMATCH, offered as {} bytes sha256 {}", download.saved.size, download.saved.sha256) };
+        log_transfer(&format!("[received] {} ({} bytes) from {} sha256 {} - {}", filename, size, download.peer, sha256, result));
+
+        // Kept in the Transfers list (as done) until the user clears it
+        download.state = TransferState::Done;
+        let peer = download.peer;
+        let receipt = download.saved.receipt.take();
+        self.publish_download(trade_id);
+
+        let mut state = STATE.lock().unwrap();
+        if !verified {
+            let nickname = state.nickname_or_id(&peer);
+            let room = state.current_room.clone();
+            state.push_system(&room, format!("⚠️ {} from {} doesn't match the file they offered", filename, nickname));
+        }
+        match receipt {
+            Some(receipt) => state.trades_to_rate.push(Trade { peer, filename: filename.clone(), sha256: sha256.clone(), verified, receipt }),
+            None => {
+                let nickname = state.nickname_or_id(&peer);
+                let room = state.current_room.clone();
+                state.push_system(&room, format!("⚠️ {} didn't send a valid receipt for {}, so they can't be rated for it", nickname, filename));
+            }
+        }
+        drop(state);
+
+        events::emit(AppEvent::FileReceived { peer: peer.to_string(), filename, sha256, verified });
+        self.start_queued(swarm);
+    }
+
+
+    /// Gives up on a download, keeping what we have of it in case the user retries it.
+    fn fail(&mut self, swarm: &mut Swarm<ChatBehaviour>, trade_id: &str) {
+
+        let Some(download) = self.downloads.get_mut(trade_id) else {
+            return;
+        };
+
+        self.chunk_queue.retain(|_, (queued, _)| queued != trade_id);
+        download.state = TransferState::Failed;
+        download.in_flight = 0;
+        download.saved.failed = true;
+
+        if let Err(e) = save_download(trade_id, &download.saved) {
+            log::info!("Failed to save download {}: {}", trade_id, e);
+        }
+
+        failed(&download.peer, &download.saved.filename);
+        self.publish_download(trade_id);
+        self.start_queued(swarm);
+    }
+
+
+    /// Updates a download in the Transfers list.
+    fn publish_download(&self, trade_id: &str) {
+
+        let Some(download) = self.downloads.get(trade_id) else {
+            return;
+        };
+
+        STATE.lock().unwrap().set_transfer(TransferStatus {
+            trade_id: trade_id.to_string(),
+            kind: TransferKind::Download,
+            peer: download.peer,
+            filename: download.saved.filename.clone(),
+            size: download.saved.size,
+            done: download.saved.offset,
+            speed: if download.state == TransferState::Active { download.speed.rate } else { 0 },
+            state: download.state,
+        });
+    }
+
+
+    /// Updates an upload in the Transfers list.
+    fn publish_upload(&self, trade_id: &str) {
+
+        let Some(upload) = self.uploads.get(trade_id) else {
+            return;
+        };
+
+        STATE.lock().unwrap().set_transfer(TransferStatus {
+            trade_id: trade_id.to_string(),
+            kind: TransferKind::Upload,
+            peer: upload.peer,
+            filename: upload.offer.filename.clone(),
+            size: upload.offer.size,
+            done: upload.sent,
+            speed: if upload.state == TransferState::Active { upload.speed.rate } else { 0 },
+            state: upload.state,
+        });
+    }
+
+
+    /// Sets the state of an upload, and updates it in the Transfers list.
+    fn set_upload_state(&mut self, trade_id: &str, state: TransferState) {
+        if let Some(upload) = self.uploads.get_mut(trade_id) {
+            upload.state = state;
+        }
+        self.publish_upload(trade_id);
+    }
+
+
+    /// Updates a file request in the Transfers list. It is shown under the request message until a file is offered.
+    fn publish_request(&self, trade_id: &str) {
+
+        let Some(request) = self.requests.get(trade_id) else {
+            return;
+        };
+
+        STATE.lock().unwrap().set_transfer(TransferStatus {
+            trade_id: trade_id.to_string(),
+            kind: TransferKind::Download,
+            peer: request.peer,
+            filename: format!("\"{}\"", request.message),
+            size: 0,
+            done: 0,
+            speed: 0,
+            state: request.state,
+        });
+    }
+
+
+    /// Forgets offers that are too old to be fetched anymore.
+    fn expire_offers(&mut self) {
+
+        let now = Utc::now().timestamp_millis();
+        let count = self.uploads.len();
+        self.uploads.retain(|_, upload| now - upload.offer.offered < OFFER_TTL);
+
+        if self.uploads.len() != count {
+            let uploads = &self.uploads;
+            STATE.lock().unwrap().transfers.retain(|status| status.kind != TransferKind::Upload || uploads.contains_key(&status.trade_id));
+            self.save_offers();
+        }
+    }
+
+
+    /// Writes the files we have offered to the data directory.
+    fn save_offers(&self) {
+
+        let offers: HashMap<&String, &Offer> = self.uploads.iter().map(|(trade_id, upload)| (trade_id, &upload.offer)).collect();
+        let saved = serde_json::to_vec(&offers).map_err(io::Error::from)
+            .and_then(|bytes| fs::create_dir_all(transfers_dir()).and_then(|_| fs::write(transfers_dir().join(OFFERS_FILE), bytes)));
+
+        if let Err(e) = saved {
+            log::info!("Failed to save {}: {}", OFFERS_FILE, e);
+        }
+    }
+}
+
+
+/// Lets the user know a file couldn't be received.
+fn failed(peer: &PeerId, filename: &str) {
+    let mut state = STATE.lock().unwrap();
+    let nickname = state.nickname_or_id(peer);
+    let room = state.current_room.clone();
+    state.push_system(&room, format!("⚠️ Failed to receive {} from {}", filename, nickname));
+}
+
+
+/// Checks a receipt is signed by the peer sending us a file, and is for this trade and the file they offered.
+fn check_receipt(signed: &SignedReceipt, trade_id: &str, saved: &SavedDownload, provider: &PeerId, own_peer_id: &PeerId) -> Result<(), String> {
+
+    let receipt = signed.verify().map_err(|e| e.to_string())?;
+
+    if receipt.trade.is_empty() || receipt.trade != trade_id {
+        return Err(format!("Receipt is for trade {}, not {}", receipt.trade, trade_id));
+    }
+    if receipt.provider != provider.to_string() || receipt.recipient != own_peer_id.to_string() {
+        return Err("Receipt is for a trade between other peers".to_string());
+    }
+    if receipt.size != saved.size || receipt.sha256 != saved.sha256 {
+        return Err("Receipt is for a different file than the one offered".to_string());
+    }
+
+    Ok(())
+}
+
+
+/// Opens a file to offer to a peer, reading it through for its size and hash.
+pub fn offer(path: &str, filename: String, peer: &PeerId) -> io::Result<(File, Offer)> {
+
+    // Kept in full, so the file can still be found after a restart from another directory
+    let path = fs::canonicalize(path)?;
+    let mut file = File::open(&path)?;
+    let (size, sha256) = hash(&mut file)?;
+
+    let offer = Offer {
+        peer: peer.to_string(),
+        path: path.to_string_lossy().to_string(),
+        filename,
+        size,
+        sha256,
+        offered: Utc::now().timestamp_millis(),
+        finished: false,
+    };
+
+    Ok((file, offer))
+}
+
+
+/// Opens a file we offered again, checking it is still the file that was offered.
+fn reopen(offer: &Offer) -> io::Result<File> {
+
+    let mut file = File::open(&offer.path)?;
+    let (size, sha256) = hash(&mut file)?;
+
+    if size != offer.size || sha256 != offer.sha256 {
+        return Err(io::Error::other("it has changed since it was offered"));
+    }
+
+    Ok(file)
+}
+
+
+/// Reads a file through, returning its size and SHA-256 (hex).
+fn hash(file: &mut File) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(file, &mut hasher)?;
+    Ok((size, util::hex(&hasher.finalize())))
+}
+
+
+/// Length of the chunk of a file starting at the given offset.
+fn chunk_length(size: u64, offset: u64) -> u64 {
+    (size - offset).min(CHUNK_SIZE)
+}
+
+
+/// Notes a chunk written to a download at the given offset, moving where the download would resume from past it and any
+/// chunks written ahead of it that now follow on without a gap. A chunk written again (eg. asked for twice) is only counted once.
+fn advance(saved: &mut SavedDownload, ahead: &mut BTreeSet<u64>, offset: u64) {
+    if offset >= saved.offset {
+        ahead.insert(offset);
+    }
+    while ahead.remove(&saved.offset) {
+        saved.offset += chunk_length(saved.size, saved.offset);
+    }
+}
+
+
+/// Where downloads in progress and our offers are kept.
+fn transfers_dir() -> PathBuf {
+    util::data_dir().join(TRANSFERS_DIR)
+}
+
+
+/// Where a download is written to until it is complete.
+fn partial_path(trade_id: &str) -> PathBuf {
+    transfers_dir().join(format!("{}.part", trade_id))
+}
+
+
+/// Where the progress of a download is kept.
+fn saved_path(trade_id: &str) -> PathBuf {
+    transfers_dir().join(format!("{}.json", trade_id))
+}
+
+
+/// Writes the progress of a download to the data directory.
+fn save_download(trade_id: &str, saved: &SavedDownload) -> io::Result<()> {
+    fs::write(saved_path(trade_id), serde_json::to_vec(saved)?)
+}
+
+
+/// Reads a download in progress from the data directory, reopening its partial file to carry on writing to. It waits for
+/// the sender to connect (or, if it had failed, for the user to retry it).
+fn load_download(trade_id: &str) -> Result<Download, Box<dyn Error>> {
+
+    let saved: SavedDownload = serde_json::from_slice(&fs::read(saved_path(trade_id))?)?;
+    let peer = saved.peer.parse()?;
+    let file = OpenOptions::new().write(true).open(partial_path(trade_id))?;
+    let state = if saved.failed { TransferState::Failed } else { TransferState::Paused };
+
+    Ok(Download {
+        peer,
+        next_offset: saved.offset,
+        speed: Speed::new(saved.offset),
+        saved,
+        file,
+        state,
+        queued_at: Instant::now(),
+        ahead: BTreeSet::new(),
+        in_flight: 0,
+        failures: 0,
+    })
+}
+
+
+/// Removes the partial file and progress of a download.
+fn remove_download_files(trade_id: &str) {
+    for path in [partial_path(trade_id), saved_path(trade_id)] {
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::info!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+
+/// Moves a file, copying it if it has to go to another filesystem (eg. a downloads directory on another drive).
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to).or_else(|_| fs::copy(from, to).map(|_| ()))
+}
+
+
+/// Adds a line to the transfer log.
+fn log_transfer(entry: &str) {
+
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(util::data_dir().join(TRANSFER_LOG))
+        .and_then(|mut log| writeln!(log, "{} {}", Local::now().format("%Y-%m-%d %H:%M:%S"), entry));
+
+    if let Err(e) = written {
+        log::info!("Failed to write to {}: {}", TRANSFER_LOG, e);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offers a file of the given size to a peer, returning the trade id.
+    fn upload(transfers: &mut Transfers, peer: PeerId, size: usize) -> String {
+
+        let path = util::test_dir().join("notes.txt");
+        fs::write(&path, (0..size).map(|i| i as u8).collect::<Vec<u8>>()).unwrap();
+
+        let (file, offer) = offer(path.to_str().unwrap(), "notes.txt".to_string(), &peer).unwrap();
+        let trade_id = util::random_id();
+        transfers.uploads.insert(trade_id.clone(), Upload { peer, offer, file: Some(file), state: TransferState::Queued, sent: 0, served: BTreeSet::new(), start: Some(0), speed: Speed::new(0) });
+
+        trade_id
+    }
+
+
+    fn chunk_request(transfers: &Transfers, trade_id: &str, offset: u64) -> ChunkRequest {
+        ChunkRequest { trade_id: trade_id.to_string(), offset, sha256: transfers.uploads[trade_id].offer.sha256.clone() }
+    }
+
+
+    fn saved_download(size: u64) -> SavedDownload {
+        SavedDownload { peer: PeerId::random().to_string(), filename: "notes.txt".to_string(), size, sha256: "00".repeat(32), offset: 0, receipt: None, failed: false }
+    }
+
+
+    #[test]
+    fn chunks_are_the_rest_of_the_file_up_to_chunk_size() {
+        assert_eq!(chunk_length(3 * CHUNK_SIZE, 0), CHUNK_SIZE);
+        assert_eq!(chunk_length(3 * CHUNK_SIZE, 2 * CHUNK_SIZE), CHUNK_SIZE);
+        assert_eq!(chunk_length(2 * CHUNK_SIZE + 10, 2 * CHUNK_SIZE), 10);
+        assert_eq!(chunk_length(10, 0), 10);
+    }
+
+
+    #[test]
+    fn downloads_resume_from_the_first_gap() {
+        let mut saved = saved_download(3 * CHUNK_SIZE + 10);
+        let mut ahead = BTreeSet::new();
+
+        // Chunks arriving out of order don't count until everything before them is in
+        advance(&mut saved, &mut ahead, 2 * CHUNK_SIZE);
+        assert_eq!(saved.offset, 0);
+        advance(&mut saved, &mut ahead, 3 * CHUNK_SIZE);
+        assert_eq!(saved.offset, 0);
+
+        advance(&mut saved, &mut ahead, 0);
+        assert_eq!(saved.offset, CHUNK_SIZE);
+
+        advance(&mut saved, &mut ahead, CHUNK_SIZE);
+        assert_eq!(saved.offset, saved.size);
+        assert!(ahead.is_empty());
+    }
+
+
+    #[test]
+    fn chunks_written_twice_are_only_counted_once() {
+        let mut saved = saved_download(2 * CHUNK_SIZE);
+        let mut ahead = BTreeSet::new();
+
+        advance(&mut saved, &mut ahead, 0);
+        advance(&mut saved, &mut ahead, 0);
+        assert_eq!(saved.offset, CHUNK_SIZE);
+        assert!(ahead.is_empty());
+    }
+
+
+    #[test]
+    fn chunks_are_only_read_at_chunk_offsets_inside_the_file() {
+        let keypair = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::default();
+        let trade_id = upload(&mut transfers, peer, 2 * CHUNK_SIZE as usize + 10);
+
+        for offset in [1, CHUNK_SIZE - 1, 2 * CHUNK_SIZE + 10, 3 * CHUNK_SIZE] {
+            let response = transfers.read_chunk(&keypair, &peer, &chunk_request(&transfers, &trade_id, offset));
+            assert!(response.data.is_empty(), "read a chunk at {}", offset);
+        }
+
+        let response = transfers.read_chunk(&keypair, &peer, &chunk_request(&transfers, &trade_id, CHUNK_SIZE));
+        assert_eq!(response.data.len() as u64, CHUNK_SIZE);
+        assert_eq!(response.data[0], CHUNK_SIZE as u8);
+    }
+
+
+    #[test]
+    fn chunks_are_only_read_by_the_peer_offered_the_file() {
+        let keypair = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::default();
+        let trade_id = upload(&mut transfers, peer, 10);
+
+        let response = transfers.read_chunk(&keypair, &PeerId::random(), &chunk_request(&transfers, &trade_id, 0));
+        assert!(response.data.is_empty());
+
+        let mut request = chunk_request(&transfers, &trade_id, 0);
+        request.sha256 = "00".repeat(32);
+        assert!(transfers.read_chunk(&keypair, &peer, &request).data.is_empty());
+
+        let mut request = chunk_request(&transfers, &trade_id, 0);
+        request.trade_id = util::random_id();
+        assert!(transfers.read_chunk(&keypair, &peer, &request).data.is_empty());
+    }
+
+
+    #[test]
+    fn receipts_come_once_every_chunk_is_sent() {
+        let keypair = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::default();
+        let trade_id = upload(&mut transfers, peer, CHUNK_SIZE as usize + 10);
+
+        // Out of order, and the first asked for again
+        let last = transfers.read_chunk(&keypair, &peer, &chunk_request(&transfers, &trade_id, CHUNK_SIZE));
+        assert!(last.receipt.is_none());
+        let first = transfers.read_chunk(&keypair, &peer, &chunk_request(&transfers, &trade_id, 0));
+        assert!(first.receipt.is_some());
+        let again = transfers.read_chunk(&keypair, &peer, &chunk_request(&transfers, &trade_id, 0));
+        assert!(again.receipt.is_some());
+
+        let upload = &transfers.uploads[&trade_id];
+        assert_eq!(upload.sent, upload.offer.size);
+        assert_eq!(upload.state, TransferState::Done);
+
+        let mut saved = saved_download(upload.offer.size);
+        saved.sha256 = upload.offer.sha256.clone();
+        assert_eq!(check_receipt(&first.receipt.unwrap(), &trade_id, &saved, &keypair.public().to_peer_id(), &peer), Ok(()));
+    }
+
+
+    #[test]
+    fn receipts_must_be_for_this_trade_and_file() {
+        let provider = Keypair::generate_ed25519();
+        let provider_id = provider.public().to_peer_id();
+        let recipient = PeerId::random();
+        let saved = saved_download(10);
+        let receipt = |trade: &str, recipient: &PeerId, size: u64, sha256: &str| {
+            SignedReceipt::new(&provider, trade.to_string(), recipient, "notes.txt".to_string(), size, sha256.to_string()).unwrap()
+        };
+
+        assert!(check_receipt(&receipt("trade", &recipient, 10, &saved.sha256), "trade", &saved, &provider_id, &recipient).is_ok());
+
+        assert!(check_receipt(&receipt("other", &recipient, 10, &saved.sha256), "trade", &saved, &provider_id, &recipient).is_err());
+        assert!(check_receipt(&receipt("", &recipient, 10, &saved.sha256), "", &saved, &provider_id, &recipient).is_err());
+        assert!(check_receipt(&receipt("trade", &PeerId::random(), 10, &saved.sha256), "trade", &saved, &provider_id, &recipient).is_err());
+        assert!(check_receipt(&receipt("trade", &recipient, 10, &saved.sha256), "trade", &saved, &PeerId::random(), &recipient).is_err());
+        assert!(check_receipt(&receipt("trade", &recipient, 11, &saved.sha256), "trade", &saved, &provider_id, &recipient).is_err());
+        assert!(check_receipt(&receipt("trade", &recipient, 10, &"11".repeat(32)), "trade", &saved, &provider_id, &recipient).is_err());
+    }
+}
@@ -1,9 +1,233 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}};
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::{Duration, Instant}};
 use libp2p::PeerId;
-use libp2p_request_response::ResponseChannel;
+use libp2p_request_response::{InboundRequestId, ResponseChannel};
 use lazy_static::lazy_static;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
 
-use crate::network::network::Response;
+use crate::{history::History, network::{message::{ChatMessage, CHAT_MESSAGE_VERSION}, network::Response, ratings::SignedReceipt}, util};
+
+
+/// How long since the last successful ping before a peer is considered stale.
+const STALE_AFTER: Duration = Duration::from_secs(45);
+
+/// Furthest ahead of the current time (in milliseconds) a message's clock can be. Allows for peers whose clocks are a
+/// little fast, without letting anyone pin their messages to the bottom of a room (or run our clock out).
+const MAX_CLOCK_SKEW: u64 = 5 * 60 * 1000;
+
+
+/// The health of the connection to a peer, as measured by the ping protocol (see ping.rs).
+#[derive(Debug, Clone, Copy)]
+pub struct PeerHealth {
+    pub rtt: Duration,
+    pub last_seen: Instant,
+    pub failures: u32,
+}
+
+
+impl PeerHealth {
+
+    /// A peer is stale if they have missed their most recent ping, or haven't answered one in a while.
+    pub fn is_stale(&self) -> bool {
+        self.failures > 0 || self.last_seen.elapsed() > STALE_AFTER
+    }
+}
+
+
+/// What a line in a chat is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// A message written by a user.
+    Chat,
+    /// Information from the application itself (eg. "✨ Welcome to the COSC473 chat!").
+    System,
+    Join,
+    Leave,
+}
+
+
+/// Whether a message made it onto the network. Only messages we send can be anything other than Received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// Sent directly to a peer (eg. a DM), waiting for them to confirm they have it.
+    Pending,
+    /// The recipient was offline, so it was left in their mailbox for when they're back (see mailbox.rs).
+    Queued,
+    Sent,
+    Failed,
+    Received,
+}
+
+
+/// A single message in a room or DM. Stored as data (rather than the line shown in the UI) so it can be rendered, sorted
+/// and searched however is needed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub id: String,
+    pub room: String,
+    pub kind: MessageKind,
+    /// PeerId of the author, None for messages from the application.
+    pub sender: Option<String>,
+    /// Nickname of the author when the message was received.
+    pub nickname: String,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: i64,
+    /// Logical clock of the message, which decides where it is shown in its room (see GlobalState::tick). 0 for messages
+    /// saved before there was one, which are ordered by timestamp instead.
+    #[serde(default)]
+    pub clock: u64,
+    pub body: String,
+    pub delivery: Delivery,
+    /// Rating of the author when the message was received.
+    pub rating: Option<i32>,
+    /// The authors signature, passed on with the message when another peer catches up on the room (see history_sync.rs).
+    #[serde(default)]
+    pub signature: Option<Vec<u8>>,
+}
+
+
+impl Message {
+
+    /// A message from the application (rather than a user) to be shown in a room.
+    pub fn system(room: &str, kind: MessageKind, body: String) -> Self {
+        Message {
+            id: util::random_id(),
+            room: room.to_string(),
+            kind,
+            sender: None,
+            nickname: String::new(),
+            timestamp: Utc::now().timestamp_millis(),
+            clock: 0,
+            body,
+            delivery: Delivery::Received,
+            rating: None,
+            signature: None,
+        }
+    }
+
+
+    /// Where the message goes in its room. Messages are shown in the order of their clocks, so everything a message's
+    /// author had seen when they wrote it is shown above it, no matter which arrived first.
+    pub fn order(&self) -> (u64, i64) {
+        (self.clock, self.timestamp)
+    }
+
+
+    /// A chat message as published to the network, either by us or someone else.
+    pub fn chat(message: ChatMessage, nickname: String, delivery: Delivery, rating: Option<i32>) -> Self {
+        Message {
+            id: message.id,
+            room: message.room,
+            kind: MessageKind::Chat,
+            sender: Some(message.sender),
+            nickname,
+            timestamp: message.timestamp,
+            clock: message.clock,
+            body: message.body,
+            delivery,
+            rating,
+            signature: message.signature,
+        }
+    }
+
+
+    /// The message as it would have been published, for passing on to other peers. None for messages from the application.
+    pub fn to_chat_message(&self) -> Option<ChatMessage> {
+        Some(ChatMessage {
+            version: CHAT_MESSAGE_VERSION,
+            id: self.id.clone(),
+            sender: self.sender.clone()?,
+            nickname: self.nickname.clone(),
+            timestamp: self.timestamp,
+            clock: self.clock,
+            room: self.room.clone(),
+            body: self.body.clone(),
+            signature: self.signature.clone(),
+        })
+    }
+}
+
+
+/// A file request received from another peer, waiting for us to respond with a file.
+#[derive(Debug)]
+pub struct IncomingRequest {
+    /// Our own number for the request, for picking it out to respond to (eg. from swapbytes-cli).
+    pub id: u64,
+    /// Id of the request in the protocol, to match its response (or failure) against.
+    pub inbound_id: InboundRequestId,
+    pub peer: PeerId,
+    pub message: String,
+    /// Id of the trade, which the receipt we send with the file is for (see ratings.rs).
+    pub trade_id: String,
+    pub channel: ResponseChannel<Response>,
+}
+
+
+/// A file we have received, which we can rate the peer who sent it for (once).
+#[derive(Debug, Clone)]
+pub struct Trade {
+    pub peer: PeerId,
+    pub filename: String,
+    /// SHA-256 of the file we received (hex).
+    pub sha256: String,
+    /// Whether the file matches the size and hash it was offered with.
+    pub verified: bool,
+    pub receipt: SignedReceipt,
+}
+
+
+/// Which way a file is going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferKind {
+    Upload,
+    Download,
+}
+
+
+/// Where a transfer is up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    /// We have asked for a file and are waiting for it to be offered.
+    Requested,
+    /// A download waiting for others to finish (see transfer.rs), or an upload the other peer hasn't started fetching.
+    Queued,
+    Active,
+    /// Waiting for the sender to connect again.
+    Paused,
+    Failed,
+    Cancelled,
+    Done,
+}
+
+
+/// A file being sent or received, as shown in the Transfers list on the "File Sharing" tab (see transfer.rs).
+#[derive(Debug, Clone)]
+pub struct TransferStatus {
+    pub trade_id: String,
+    pub kind: TransferKind,
+    pub peer: PeerId,
+    /// Name of the file, or the request message until a file has been offered.
+    pub filename: String,
+    pub size: u64,
+    /// Bytes received (or sent) so far.
+    pub done: u64,
+    /// Bytes per second, over the last second or so.
+    pub speed: u64,
+    pub state: TransferState,
+}
+
+
+impl TransferStatus {
+
+    /// Roughly how long until the transfer is complete, at its current speed.
+    pub fn eta(&self) -> Option<Duration> {
+        (self.state == TransferState::Active && self.speed > 0).then(|| Duration::from_secs(self.size.saturating_sub(self.done) / self.speed))
+    }
+}
 
 
 /// The state of our application. Includes items such as the users nickname, a list of all connected pairs,
@@ -12,18 +236,258 @@ use crate::network::network::Response;
 pub struct GlobalState {
     pub nickname: String,
     pub nicknames: HashMap<String, String>,
+    pub client_versions: HashMap<String, String>,
+    pub peer_health: HashMap<String, PeerHealth>,
     pub peer_id: String,
     pub peers: Vec<PeerId>,
     pub rooms: Vec<String>,
-    pub messages: HashMap<String, Vec<String>>,
-    pub requests: Vec<(PeerId, String, ResponseChannel<Response>)>,
+    pub messages: HashMap<String, Vec<Message>>,
+    pub requests: Vec<IncomingRequest>,
     pub notifications: HashMap<String, bool>,
-    pub current_rating: Option<PeerId>,
+    pub trades_to_rate: Vec<Trade>,
+    pub transfers: Vec<TransferStatus>,
     pub current_room: String,
+    pub history: Option<History>,
+    /// Our logical clock, for ordering messages (see tick).
+    pub clock: u64,
+    /// Number given to the last file request we received (see next_request_id).
+    pub last_request_id: u64,
 }
 
 impl GlobalState {
 
+    /// Advances our clock for a message we are about to send, returning its new value. This is a Lamport clock (it is
+    /// always past every message we have seen) that is also kept at least at the current time, so messages from peers that
+    /// haven't seen each other yet are still shown roughly in the order they were written.
+    pub fn tick(&mut self) -> u64 {
+        self.clock = self.clock.saturating_add(1).max(now());
+        self.clock
+    }
+
+
+    /// Adds a message to its room, in clock order. Every message shown in a chat goes through here, and chat messages are
+    /// saved to the history (if there is one) so they are still there next time. Messages we already have are skipped.
+    pub fn push_message(&mut self, mut message: Message) {
+
+        message.clock = clamp_clock(message.clock);
+        self.clock = self.clock.max(message.clock);
+
+        let messages = self.messages.entry(message.room.clone()).or_default();
+        if messages.iter().any(|existing| existing.id == message.id) {
+            return;
+        }
+
+        let index = messages.partition_point(|existing| existing.order() <= message.order());
+        messages.insert(index, message.clone());
+
+        if let (Some(history), MessageKind::Chat) = (&mut self.history, message.kind) {
+            if let Err(e) = history.append(&message) {
+                log::info!("Failed to save message {} to history: {}", message.id, e);
+            }
+        }
+    }
+
+
+    /// Updates the delivery state of one of our messages (eg. once a DM has been confirmed).
+    pub fn set_delivery(&mut self, room: &str, id: &str, delivery: Delivery) {
+
+        let Some(message) = self.messages.get_mut(room).and_then(|messages| messages.iter_mut().find(|message| message.id == id)) else {
+            return;
+        };
+
+        message.delivery = delivery;
+
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.update(message) {
+                log::info!("Failed to save delivery of message {}: {}", id, e);
+            }
+        }
+    }
+
+
+    /// Updates the rating shown next to a message, once it has been fetched (see kademlia.rs).
+    pub fn set_rating(&mut self, room: &str, id: &str, rating: i32) {
+
+        let Some(message) = self.messages.get_mut(room).and_then(|messages| messages.iter_mut().find(|message| message.id == id)) else {
+            return;
+        };
+
+        message.rating = Some(rating);
+
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.update(message) {
+                log::info!("Failed to save rating of message {}: {}", id, e);
+            }
+        }
+    }
+
+
+    /// Starts saving chat messages to the given history, loading the most recent messages of every room from it.
+    pub fn load_history(&mut self, mut history: History) -> std::io::Result<()> {
+
+        for (room, mut messages) in history.load_recent()? {
+            messages.iter_mut().for_each(|message| message.clock = clamp_clock(message.clock));
+            messages.sort_by_key(Message::order);
+            self.clock = messages.iter().map(|message| message.clock).fold(self.clock, u64::max);
+            self.messages.entry(room).or_default().splice(0..0, messages);
+        }
+
+        self.history = Some(history);
+        Ok(())
+    }
+
+
+    /// The newest chat message we have for a room.
+    pub fn newest_message(&self, room: &str) -> Option<&Message> {
+        self.messages.get(room)?.iter().filter(|message| message.kind == MessageKind::Chat).max_by_key(|message| message.timestamp)
+    }
+
+
+    /// Chat messages of a room from the given timestamp on, or after the message with the given id if we have it. Read from
+    /// the history when there is one, as it goes further back than what is loaded.
+    pub fn messages_since(&self, room: &str, since: i64, after: Option<&str>) -> Vec<Message> {
+
+        let mut messages = match &self.history {
+            Some(history) => history.read(room).unwrap_or_else(|e| {
+                log::info!("Failed to read history of {}: {}", room, e);
+                vec![]
+            }),
+            None => self.messages.get(room).cloned().unwrap_or_default(),
+        };
+
+        messages.retain(|message| message.kind == MessageKind::Chat);
+
+        match after.and_then(|after| messages.iter().position(|message| message.id == after)) {
+            Some(index) => messages.split_off(index + 1),
+            None => messages.into_iter().filter(|message| message.timestamp >= since).collect(),
+        }
+    }
+
+
+    /// Merges messages from elsewhere (eg. another peer) into a room in clock order, skipping any we already have.
+    /// Returns how many were new.
+    pub fn merge_messages(&mut self, room: &str, messages: Vec<Message>) -> usize {
+
+        let loaded = self.messages.entry(room.to_string()).or_default();
+        let mut new = vec![];
+
+        for mut message in messages {
+            if loaded.iter().any(|existing| existing.id == message.id) || new.iter().any(|existing: &Message| existing.id == message.id) {
+                continue;
+            }
+            message.clock = clamp_clock(message.clock);
+            self.clock = self.clock.max(message.clock);
+            let index = loaded.partition_point(|existing| existing.order() <= message.order());
+            loaded.insert(index, message.clone());
+            new.push(message);
+        }
+
+        if let (Some(history), false) = (&mut self.history, new.is_empty()) {
+            if let Err(e) = history.merge(room, &new) {
+                log::info!("Failed to save synced messages of {}: {}", room, e);
+            }
+        }
+
+        new.len()
+    }
+
+
+    /// Loads the page of a room's history before the messages already shown. Returns how many messages were loaded.
+    pub fn load_older(&mut self, room: &str) -> usize {
+
+        let Some(history) = &self.history else {
+            return 0;
+        };
+
+        // Everything newer than what is saved is shown, so the page is the most recent of the saved messages that aren't
+        let messages = self.messages.entry(room.to_string()).or_default();
+        let shown: HashSet<&str> = messages.iter().map(|message| message.id.as_str()).collect();
+
+        match history.page(room, &shown) {
+            Ok(older) => {
+                // Put in place like any other message
+                let count = older.len();
+                for message in older {
+                    let index = messages.partition_point(|existing| existing.order() <= message.order());
+                    messages.insert(index, message);
+                }
+                count
+            }
+            Err(e) => {
+                log::info!("Failed to load history of {}: {}", room, e);
+                0
+            }
+        }
+    }
+
+
+    /// Numbers a file request we have just received. Requests are numbered from 1, in the order they arrive.
+    pub fn next_request_id(&mut self) -> u64 {
+        self.last_request_id += 1;
+        self.last_request_id
+    }
+
+
+    /// Shows a message from the application in a room.
+    pub fn push_system(&mut self, room: &str, text: String) {
+        let mut message = Message::system(room, MessageKind::System, text);
+        message.clock = self.tick();
+        self.push_message(message);
+    }
+
+
+    /// Shows a network event (eg. a peer joining or leaving) in the Global chat.
+    pub fn announce(&mut self, kind: MessageKind, text: String) {
+        let mut message = Message::system("Global", kind, text);
+        message.clock = self.tick();
+        self.push_message(message);
+    }
+
+
+    /// Starts a room (or DM) with a welcome message, if it isn't already started. The welcome message is dated (and
+    /// clocked) at the start of time, so it stays at the top even when older messages are merged in.
+    pub fn open_room(&mut self, room: &str, welcome: String) {
+        if !self.messages.contains_key(room) {
+            let mut message = Message::system(room, MessageKind::System, welcome);
+            message.timestamp = 0;
+            self.push_message(message);
+        }
+    }
+
+
+    /// Takes the most recent trade with a peer that we haven't rated yet.
+    pub fn take_trade(&mut self, peer: &PeerId) -> Option<Trade> {
+        let index = self.trades_to_rate.iter().rposition(|trade| trade.peer == *peer)?;
+        Some(self.trades_to_rate.remove(index))
+    }
+
+
+    /// Adds a transfer to the Transfers list, or updates it if it is already there.
+    pub fn set_transfer(&mut self, status: TransferStatus) {
+        match self.transfers.iter_mut().find(|existing| existing.trade_id == status.trade_id && existing.kind == status.kind) {
+            Some(existing) => *existing = status,
+            None => self.transfers.push(status),
+        }
+    }
+
+
+    /// The nickname of a peer, or a shortened PeerId if we don't know it yet.
+    pub fn nickname_or_id(&self, peer_id: &PeerId) -> String {
+        let peer_id = peer_id.to_string();
+        self.nicknames.get(&peer_id).cloned().unwrap_or_else(|| format!("…{}", &peer_id[peer_id.len().saturating_sub(6)..]))
+    }
+
+
+    /// Connected peers whose nickname we know, in the order they are listed in the UI. Peers are only shown once we know
+    /// who they are, so lists and selections in the UI must both use this to line up.
+    pub fn named_peers(&self) -> Vec<(PeerId, String)> {
+        self.peers
+            .iter()
+            .filter_map(|peer_id| self.nicknames.get(&peer_id.to_string()).map(|nickname| (*peer_id, nickname.clone())))
+            .collect()
+    }
+
+
     /// Sets the initial values of the Global State (Specifically the current room and the default rooms).
     fn new() -> GlobalState {
     
@@ -40,7 +504,101 @@ impl GlobalState {
 }
 
 
+/// The current time in milliseconds since the Unix epoch, which our clock is kept at least at (see GlobalState::tick).
+fn now() -> u64 {
+    Utc::now().timestamp_millis().max(0) as u64
+}
+
+
+/// A clock from another peer, pulled back to no further ahead of the current time than MAX_CLOCK_SKEW.
+fn clamp_clock(clock: u64) -> u64 {
+    clock.min(now().saturating_add(MAX_CLOCK_SKEW))
+}
+
+
 // Creates a static instance of the GlobalState to be accessed throughout the application.
 lazy_static! {
     pub static ref STATE: Arc<Mutex<GlobalState>> = Arc::new(Mutex::new(GlobalState::new()));
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chat message in the given room, with the given clock.
+    fn message(room: &str, clock: u64) -> Message {
+        Message { clock, ..Message::system(room, MessageKind::Chat, format!("message {}", clock)) }
+    }
+
+
+    #[test]
+    fn clocks_too_far_ahead_are_pulled_back() {
+        let start = now();
+        assert_eq!(clamp_clock(5), 5);
+        assert_eq!(clamp_clock(start), start);
+
+        let clamped = clamp_clock(u64::MAX);
+        assert!(clamped >= start + MAX_CLOCK_SKEW && clamped <= now() + MAX_CLOCK_SKEW);
+    }
+
+
+    #[test]
+    fn our_clock_is_past_every_message_seen() {
+        let mut state = GlobalState::default();
+
+        let first = state.tick();
+        assert!(first >= now() - 1000);
+        assert!(state.tick() > first);
+
+        // A message from a peer whose clock is far ahead can't run ours out
+        state.push_message(message("room", u64::MAX));
+        let seen = state.messages["room"][0].clock;
+        assert!(seen < u64::MAX);
+        assert!(state.tick() > seen);
+
+        state.clock = u64::MAX;
+        assert_eq!(state.tick(), u64::MAX);
+    }
+
+
+    #[test]
+    fn messages_are_shown_in_clock_order() {
+        let mut state = GlobalState::default();
+
+        for clock in [3, 1, 2] {
+            state.push_message(message("room", clock));
+        }
+        let duplicate = state.messages["room"][0].clone();
+        state.push_message(duplicate);
+
+        let clocks: Vec<u64> = state.messages["room"].iter().map(|message| message.clock).collect();
+        assert_eq!(clocks, vec![1, 2, 3]);
+    }
+
+
+    #[test]
+    fn older_messages_are_put_in_place_without_duplicates() {
+        let mut history = History::open(&util::test_dir(), 1000).unwrap();
+        let saved: Vec<Message> = (0..150).map(|clock| message("room", clock)).collect();
+        saved.iter().for_each(|message| history.append(message).unwrap());
+
+        // The most recent page is shown, below one older message already caught up on, and above a welcome message
+        let mut state = GlobalState::default();
+        let mut shown = vec![saved[10].clone()];
+        shown.extend(history.page("room", &HashSet::new()).unwrap());
+        state.messages.insert("room".to_string(), shown);
+        state.history = Some(history);
+        state.push_system("room", "✨ Welcome".to_string());
+
+        assert_eq!(state.load_older("room"), 49);
+
+        let messages = &state.messages["room"];
+        let clocks: Vec<u64> = messages.iter().filter(|message| message.kind == MessageKind::Chat).map(|message| message.clock).collect();
+        assert_eq!(clocks, (0..150).collect::<Vec<u64>>());
+        assert_eq!(messages.last().unwrap().kind, MessageKind::System);
+
+        // Nothing older is left to load
+        assert_eq!(state.load_older("room"), 0);
+    }
+}
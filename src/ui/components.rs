@@ -1,5 +1,6 @@
 use ratatui::{layout::Alignment, style::{Color, Style}, text::Line, widgets::{Block, List, ListItem, Paragraph}};
 use tui_big_text::{BigText, PixelSize};
+use crate::state::PeerHealth;
 use strum_macros::EnumIter;
 
 // List of all application tabs.
@@ -28,7 +29,7 @@ impl Tab {
 
 // Re-useable component to display a list of messages. The title will be placed in the border 
 // of the box. Typically the room name or the users nickname.
-pub fn message_component(messages: &str, title: String) -> Paragraph {
+pub fn message_component(messages: &str, title: String) -> Paragraph<'_> {
     
     let messages = Paragraph::new(messages)
             .block(
@@ -43,7 +44,7 @@ pub fn message_component(messages: &str, title: String) -> Paragraph {
 
 // Re-useable component to display an input for the user. The title will be placed in the border 
 // of the box and will act as instructions for the input.
-pub fn input_component(input_str: &str, title: String) -> Paragraph{
+pub fn input_component(input_str: &str, title: String) -> Paragraph<'_> {
 
     let input = Paragraph::new(input_str)
         .block(
@@ -84,9 +85,9 @@ pub fn title_component(text: Vec<Line>) -> BigText {
 
 
 // Re-useable notification component.
-pub fn notification_component(text: &String, title: String) -> Paragraph {
+pub fn notification_component(text: &str, title: String) -> Paragraph<'_> {
 
-    let notification = Paragraph::new(text.clone())
+    let notification = Paragraph::new(text.to_string())
             .block(
                 Block::bordered()
                     .style(Style::default().fg(Color::Blue)).title(title)
@@ -97,3 +98,12 @@ pub fn notification_component(text: &String, title: String) -> Paragraph {
     notification
 }
 
+
+// Formats a peers nickname with the health of our connection to them, eg. "Geordie (23 ms)" or "Geordie (stale)".
+pub fn peer_label(nickname: &str, health: Option<&PeerHealth>) -> String {
+    match health {
+        Some(health) if health.is_stale() => format!("{} (⚠️ stale)", nickname),
+        Some(health) => format!("{} ({} ms)", nickname, health.rtt.as_millis()),
+        None => nickname.to_string(),
+    }
+}
@@ -1,6 +1,7 @@
 use std::rc::Rc;
 
-use crate::{network::client::Client, state::STATE, ui::components::{input_component, message_component}};
+use crate::{network::client::Client, state::{Delivery, Message, MessageKind, STATE}, ui::components::{input_component, message_component}};
+use libp2p::Multiaddr;
 use ratatui::{
     crossterm::event::{KeyCode, KeyEvent},
     prelude::*,
@@ -9,7 +10,13 @@ use ratatui::{
 /// A page for users to chat with all other peers on the network.
 #[derive(Default)]
 pub struct Chat {
-    input: String
+    input: String,
+    room: String,
+    /// How many lines the view is scrolled up from the newest message.
+    scroll: u16,
+    /// Lines of messages that fit in the view, and how far it can be scrolled, as of the last render.
+    height: u16,
+    max_scroll: u16,
 }
 
 impl Chat {
@@ -19,13 +26,29 @@ impl Chat {
     pub fn render(&mut self, frame: &mut Frame, layout: Rc<[Rect]>) {
 
         let (room_key, nickname) = self.room_setup();
+
+        // Start each room at its newest messages
+        if room_key != self.room {
+            self.room = room_key.clone();
+            self.scroll = 0;
+        }
         
         // Messages
-        let messages = self.format_messages(room_key);
-        let messages_display = message_component(&messages, nickname);
+        let messages = {
+            let state = STATE.lock().unwrap();
+            format_messages(state.messages.get(&room_key).map(Vec::as_slice).unwrap_or_default(), &state.peer_id)
+        };
+
+        // Keep the newest messages in view, unless scrolled back
+        let lines = u16::try_from(messages.lines().count()).unwrap_or(u16::MAX);
+        self.height = layout[1].height.saturating_sub(2);
+        self.max_scroll = lines.saturating_sub(self.height);
+        self.scroll = self.scroll.min(self.max_scroll);
+
+        let messages_display = message_component(&messages, nickname).scroll((self.max_scroll - self.scroll, 0));
     
         // User input
-        let input_display = input_component(self.input.as_str(), "Type Message | <Enter> to send".to_string());
+        let input_display = input_component(self.input.as_str(), "Type Message | <Enter> to send | <PgUp/PgDn> to scroll | /connect <multiaddr> to dial a peer".to_string());
     
         // Render
         frame.render_widget(messages_display, layout[1]);
@@ -48,6 +71,15 @@ impl Chat {
             KeyCode::Backspace => {
                 self.input.pop();
             }
+
+            // Scroll back through older messages
+            KeyCode::PageUp => {
+                self.scroll_up();
+            }
+
+            KeyCode::PageDown => {
+                self.scroll = self.scroll.saturating_sub(self.height.max(1));
+            }
     
             // Submit a message. Adds the message to the local message list and sends a
             // network request to share it with other peers subscribed to the topic.
@@ -55,16 +87,17 @@ impl Chat {
                 
                 let message = self.input.to_string();
 
-                if !message.is_empty() {
+                // Only the whole command word, so eg. "/connections" is sent as a message
+                let (command, argument) = message.split_once(' ').unwrap_or((message.as_str(), ""));
 
-                    let mut state = STATE.lock().unwrap();
-                    let room_key = state.current_room.clone();
-    
-                    // Add to local storage
-                    let msgs = state.messages.entry(room_key.clone()).or_default();
-                    msgs.push(format!("{}: {}", "You".to_string(), message.clone()));
+                if command == "/connect" {
+                    self.connect(client, argument.trim()).await;
+                    self.input.clear();
+                } else if !message.is_empty() {
+
+                    let room_key = STATE.lock().unwrap().current_room.clone();
 
-                    // Send message to the network
+                    // Send message to the network (which also adds it to local storage)
                     client.send_message(message, room_key).await;
 
                     self.input.clear();
@@ -76,15 +109,37 @@ impl Chat {
     }
 
 
-    /// Fetches messages for the room from the global store and formats them in a way to be displayed in the Ratatui UI.
-    fn format_messages(&self, room: String) -> String {
+    /// Scrolls up a page. Once the oldest loaded message is reached, the page before it is loaded from the history.
+    fn scroll_up(&mut self) {
 
-        let state = STATE.lock().unwrap();
-        let messages = state.messages.get(&room).expect("Rooms should be in the messages HashMap at all times");
-        let message_str: Vec<String> = messages.iter().map(|m| format!("{}", m)).collect();
-        let concatenated_messages = message_str.join("\n");
+        let page = self.height.max(1);
 
-        concatenated_messages
+        if self.scroll.saturating_add(page) > self.max_scroll {
+            let mut state = STATE.lock().unwrap();
+            let room = state.current_room.clone();
+            state.load_older(&room);
+        }
+
+        self.scroll = self.scroll.saturating_add(page);
+    }
+
+
+    /// Handles the "/connect <multiaddr>" action, dialing a peer that can't be discovered on the local network.
+    async fn connect(&mut self, client: &mut Client, address: &str) {
+
+        let status = match address.parse::<Multiaddr>() {
+            _ if address.is_empty() => "⚠️ Usage: /connect /ip4/<ip>/tcp/<port>/p2p/<PeerId>".to_string(),
+            Ok(address) => {
+                let status = format!("🔌 Connecting to {}...", address);
+                client.dial(address).await;
+                status
+            }
+            Err(_) => format!("⚠️ Invalid address \"{}\". Usage: /connect /ip4/<ip>/tcp/<port>/p2p/<PeerId>", address),
+        };
+
+        let mut state = STATE.lock().unwrap();
+        let room_key = state.current_room.clone();
+        state.push_system(&room_key, status);
     }
 
 
@@ -99,11 +154,39 @@ impl Chat {
         // Clear notifications for the room
         state.notifications.insert(room.clone(), false);
 
-        if !state.messages.contains_key(&room_key) {
-            state.messages.entry(room.clone()).or_insert(vec![format!("✨ Welcome to the {} chat!", &room)]);
-        }
-        
+        state.open_room(&room, format!("✨ Welcome to the {} chat!", &room));
+
         (room_key, nickname)
     }
 
-}
\ No newline at end of file
+}
+
+
+/// Formats the messages of a room into the lines displayed in the Ratatui UI.
+pub fn format_messages(messages: &[Message], own_peer_id: &str) -> String {
+    messages.iter().map(|message| format_message(message, own_peer_id)).collect::<Vec<_>>().join("\n")
+}
+
+
+/// Formats a single message. Messages from others are shown with an emoji for the senders rating when it was received.
+fn format_message(message: &Message, own_peer_id: &str) -> String {
+
+    if message.kind != MessageKind::Chat {
+        return message.body.clone();
+    }
+
+    if message.sender.as_deref() == Some(own_peer_id) {
+        return match message.delivery {
+            Delivery::Pending => format!("You: {} (sending...)", message.body),
+            Delivery::Queued => format!("You: {} (📬 waiting for them to come online)", message.body),
+            Delivery::Failed => format!("You: {} (⚠️ not sent)", message.body),
+            _ => format!("You: {}", message.body),
+        };
+    }
+
+    match message.rating {
+        Some(rating) if rating > 0 => format!("😇 {}: {}", message.nickname, message.body),
+        Some(rating) if rating < 0 => format!("👿 {}: {}", message.nickname, message.body),
+        _ => format!("{}: {}", message.nickname, message.body),
+    }
+}
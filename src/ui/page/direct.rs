@@ -1,5 +1,5 @@
-use std::rc::Rc;
-use crate::{network::client::Client, state::STATE, ui::components::{input_component, list_component}};
+use std::{rc::Rc, time::Duration};
+use crate::{network::client::Client, state::{TransferKind, TransferState, STATE}, ui::components::{input_component, list_component, peer_label}, util};
 use ratatui::{
     crossterm::event::{KeyCode, KeyEvent},
     prelude::*,
@@ -12,7 +12,8 @@ enum Section {
     #[default]
     None,
     Request,
-    Response
+    Response,
+    Transfers
 }
 
 
@@ -22,6 +23,7 @@ pub struct Direct {
     input: String,
     peer_list_state: ListState,
     request_list_state: ListState,
+    transfer_list_state: ListState,
     selected_section: Section,
     popup: Section
 }
@@ -30,6 +32,16 @@ impl Direct {
 
     pub fn render(&mut self, frame: &mut Frame, layout: Rc<[Rect]>) {
     
+        // Splits the screen to have the Transfers list below the Request and Received lists.
+        let vertical_layout = Layout::new(
+            Direction::Vertical,
+            [
+                Constraint::Percentage(55),
+                Constraint::Percentage(45),
+            ],
+        )
+        .split(layout[1]);
+
         // Allows to split the screen to have both Request and Received lists.
         let horizontal_layout = Layout::new(
             Direction::Horizontal,
@@ -38,7 +50,7 @@ impl Direct {
                 Constraint::Percentage(50),
             ],
         )
-        .split(layout[1]);
+        .split(vertical_layout[0]);
 
         // Request a file section
         let peer_items = self.format_peers();
@@ -50,6 +62,12 @@ impl Direct {
         let request_items = self.format_requests();
         let requests_display = list_component(request_items, "🚀 Incoming Request".to_string());
         frame.render_stateful_widget(requests_display, horizontal_layout[1], &mut self.request_list_state.clone());
+
+
+        // Transfers section
+        let transfer_items = self.format_transfers();
+        let transfers_display = list_component(transfer_items, "📦 Transfers | <t> to select, <c> to cancel / clear, <r> to retry".to_string());
+        frame.render_stateful_widget(transfers_display, vertical_layout[1], &mut self.transfer_list_state.clone());
     
     
         // Display the input for request messages and response file paths when required
@@ -62,7 +80,7 @@ impl Direct {
                 let popup = input_component(&self.input, "Enter a File Path | <Enter> to send".to_string());
                 frame.render_widget(popup, layout[2]);
             }
-            Section::None => {}
+            Section::None | Section::Transfers => {}
         }
     }
     
@@ -72,6 +90,28 @@ impl Direct {
         
         match key.code {
 
+            // Selects the "Transfers" section
+            KeyCode::Char('t') if self.popup == Section::None => {
+                self.selected_section = Section::Transfers;
+                self.peer_list_state.select(None);
+                self.request_list_state.select(None);
+                self.transfer_list_state.select_first();
+            }
+
+            // Cancels the selected transfer, or clears it from the list if it is over
+            KeyCode::Char('c') if self.popup == Section::None && self.selected_section == Section::Transfers => {
+                if let Some((trade_id, kind)) = self.selected_transfer() {
+                    client.cancel_transfer(trade_id, kind).await;
+                }
+            }
+
+            // Retries the selected transfer
+            KeyCode::Char('r') if self.popup == Section::None && self.selected_section == Section::Transfers => {
+                if let Some((trade_id, kind)) = self.selected_transfer() {
+                    client.retry_transfer(trade_id, kind).await;
+                }
+            }
+
             // User input into the message box
             KeyCode::Char(c) => {
                 self.input.push(c);
@@ -83,43 +123,39 @@ impl Direct {
             }
 
             // Moves down the currently selected list
-            KeyCode::Down => {
-                if self.popup == Section::None {
-                    match self.selected_section {
-                        Section::Request => self.peer_list_state.select_next(),
-                        Section::Response => self.request_list_state.select_next(),
-                        Section::None => {}
-                    }
+            KeyCode::Down if self.popup == Section::None => {
+                match self.selected_section {
+                    Section::Request => self.peer_list_state.select_next(),
+                    Section::Response => self.request_list_state.select_next(),
+                    Section::Transfers => self.transfer_list_state.select_next(),
+                    Section::None => {}
                 }
             }
 
             // Moves up the currently selected list
-            KeyCode::Up => {
-                if self.popup == Section::None {
-                    match self.selected_section {
-                        Section::Request => self.peer_list_state.select_previous(),
-                        Section::Response => self.request_list_state.select_previous(),
-                        Section::None => {}
-                    }
+            KeyCode::Up if self.popup == Section::None => {
+                match self.selected_section {
+                    Section::Request => self.peer_list_state.select_previous(),
+                    Section::Response => self.request_list_state.select_previous(),
+                    Section::Transfers => self.transfer_list_state.select_previous(),
+                    Section::None => {}
                 }
             }
 
             // Selects the "Send Request" section
-            KeyCode::Left => {
-                if self.popup == Section::None {
-                    self.selected_section = Section::Request;
-                    self.request_list_state.select(None);
-                    self.peer_list_state.select_first();
-                }
+            KeyCode::Left if self.popup == Section::None => {
+                self.selected_section = Section::Request;
+                self.request_list_state.select(None);
+                self.transfer_list_state.select(None);
+                self.peer_list_state.select_first();
             }
 
             // Selects the "Incoming Requests" section
-            KeyCode::Right => {
-                if self.popup == Section::None {
-                    self.selected_section = Section::Response;
-                    self.peer_list_state.select(None);
-                    self.request_list_state.select_first();
-                }
+            KeyCode::Right if self.popup == Section::None => {
+                self.selected_section = Section::Response;
+                self.peer_list_state.select(None);
+                self.transfer_list_state.select(None);
+                self.request_list_state.select_first();
             }
 
             // Handles confirmation of the current popup
@@ -127,7 +163,7 @@ impl Direct {
                 match self.selected_section {
                     Section::Request => self.handle_requests(client).await,
                     Section::Response => self.handle_response(client).await,
-                    Section::None => {}
+                    Section::None | Section::Transfers => {}
                 }
             }
 
@@ -137,16 +173,14 @@ impl Direct {
 
 
     /// Fetches connected peers from the global store and formats them in a way to be displayed in the Ratatui UI.
-    fn format_peers(&self) -> Vec<ListItem> {
+    fn format_peers(&self) -> Vec<ListItem<'_>> {
 
         let state = STATE.lock().unwrap();
 
         let peers: Vec<ListItem> = state
-        .peers
-        .iter()
-        .filter_map(|peer_id| {
-            state.nicknames.get(&peer_id.to_string()).map(|nickname| ListItem::new(format!("{}", nickname.clone())))
-        })
+        .named_peers()
+        .into_iter()
+        .map(|(peer_id, nickname)| ListItem::new(peer_label(&nickname, state.peer_health.get(&peer_id.to_string()))))
         .collect();
 
         peers
@@ -154,31 +188,73 @@ impl Direct {
 
 
     /// Fetches current incoming requests from the global store and formats them in a way to be displayed in the Ratatui UI.
-    fn format_requests(&self) -> Vec<ListItem>  {
+    fn format_requests(&self) -> Vec<ListItem<'_>>  {
 
         let state = STATE.lock().unwrap();
         
         let request_items: Vec<ListItem> = state
             .requests.iter()
-            .map(|request| ListItem::new(format!("{} - {}", state.nicknames.get(&request.0.to_string().clone()).expect(""), request.1)))
+            .map(|request| ListItem::new(format!("{} - {}", state.nickname_or_id(&request.peer), request.message)))
             .collect();  
 
         request_items
     }
 
 
+    /// Fetches transfers from the global store and formats them in a way to be displayed in the Ratatui UI.
+    /// Eg. "⬇️ notes.pdf from Ben - 12.3 MB / 45.0 MB (27%) · 1.2 MB/s · ETA 27s · Active"
+    fn format_transfers(&self) -> Vec<ListItem<'_>> {
+
+        let state = STATE.lock().unwrap();
+
+        let transfer_items: Vec<ListItem> = state
+            .transfers.iter()
+            .map(|transfer| {
+                let (icon, direction) = match transfer.kind {
+                    TransferKind::Upload => ("⬆️", "to"),
+                    TransferKind::Download => ("⬇️", "from"),
+                };
+                let mut item = format!("{} {} {} {}", icon, transfer.filename, direction, state.nickname_or_id(&transfer.peer));
+
+                // Requests don't have a file yet
+                if transfer.state != TransferState::Requested {
+                    let percent = (transfer.done * 100).checked_div(transfer.size).unwrap_or(100);
+                    item += &format!(" - {} / {} ({}%)", util::format_size(transfer.done), util::format_size(transfer.size), percent);
+                }
+                if transfer.state == TransferState::Active {
+                    item += &format!(" · {}/s", util::format_size(transfer.speed));
+                }
+                if let Some(eta) = transfer.eta() {
+                    item += &format!(" · ETA {}", format_eta(eta));
+                }
+
+                ListItem::new(format!("{} · {:?}", item, transfer.state))
+            })
+            .collect();
+
+        transfer_items
+    }
+
+
+    /// The trade id and kind of the selected transfer, if there is one.
+    fn selected_transfer(&self) -> Option<(String, TransferKind)> {
+        let index = self.transfer_list_state.selected()?;
+        STATE.lock().unwrap().transfers.get(index).map(|transfer| (transfer.trade_id.clone(), transfer.kind))
+    }
+
+
     /// Handles events in the "Request a File" section.
     /// If a user is selected and the request popup is not already showing, the request input popup will be displayed.
     /// Otherwise if it is already showing, the request with the message typed into the input will be sent to the selected user.
     async fn handle_requests(&mut self, client: &mut Client) {
 
-        let state = STATE.lock().unwrap();
+        let peers = STATE.lock().unwrap().named_peers();
 
         if let Some(selected_index) = self.peer_list_state.selected() {
-            if state.peers.len() > 0 {
+            if !peers.is_empty() {
                 if self.popup != Section::Request {
                     self.popup = Section::Request;
-                } else if let Some(selected_user) = state.peers.get(selected_index) {
+                } else if let Some((selected_user, _)) = peers.get(selected_index) {
                     client.send_request(self.input.clone(), *selected_user).await;
                     self.reset_popup();
                 }
@@ -192,15 +268,13 @@ impl Direct {
     /// Otherwise if it is already showing, the response with the file at the given path will be sent to the selected user.
     async fn handle_response(&mut self, client: &mut Client) {
 
-        let mut state = STATE.lock().unwrap();
-
         if let Some(selected_index) = self.request_list_state.selected() {
-            if state.requests.len() > 0 {
+            if selected_index < STATE.lock().unwrap().requests.len() {
                 if self.popup != Section::Response {
                     self.popup = Section::Response;
                 } else {
-                    let (_, _, channel) = state.requests.remove(selected_index);
-                    client.send_response("swapbytes.txt".to_string(), self.input.to_string(), channel).await;
+                    let request = STATE.lock().unwrap().requests.remove(selected_index);
+                    client.send_response(util::file_name(&self.input), self.input.to_string(), request).await;
                     self.reset_popup();
                 }
             }
@@ -213,4 +287,15 @@ impl Direct {
         self.input.clear();
     }
 
-}
\ No newline at end of file
+}
+
+
+/// Formats how long a transfer has left, eg. "2m 05s".
+fn format_eta(eta: Duration) -> String {
+    let secs = eta.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
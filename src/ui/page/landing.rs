@@ -77,12 +77,10 @@ impl Landing {
                         }
 
                         // Submits the nickname and will proceed to enter the main application
-                        KeyCode::Enter => {
-                            if !self.input.is_empty() {
-                                state.nickname = self.input.to_string();
-                                self.input = String::new();
-                                return Ok(true)
-                            }
+                        KeyCode::Enter if !self.input.is_empty() => {
+                            state.nickname = self.input.to_string();
+                            self.input = String::new();
+                            return Ok(true)
                         }
 
                         _ => {}
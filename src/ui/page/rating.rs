@@ -13,14 +13,23 @@ pub struct Rating {}
 impl Rating{
 
     /// Simply renders the page consisting of the notification that a user has sent you a file. The user is prompted to give them a rating
-    /// depending on whether they recieved the correct file (Good, Neutral, Bad).
+    /// depending on whether they recieved the correct file (Good, Neutral, Bad). Each trade can only be rated once.
     pub fn render(&mut self, frame: &mut Frame, layout: Rc<[Rect]>) {
 
         let state = STATE.lock().unwrap();
-        let peer_id = state.current_rating.unwrap();
+        let Some(trade) = state.trades_to_rate.first() else {
+            return;
+        };
     
-        let text = format!("\n\nYou have just received a file! \nGive {} a rating for this trade: \n\n1: Bad  2: Neutral  3: Good",
-         state.nicknames.get(&peer_id.to_string()).unwrap());
+        // Whether they sent exactly the file they offered, to help decide on a rating
+        let check = if trade.verified {
+            format!("✅ Matches the file offered \nsha256 {}", trade.sha256)
+        } else {
+            "❌ Doesn't match the file offered!".to_string()
+        };
+
+        let text = format!("\n\nYou have just received {}! \n{} \nGive {} a rating for this trade: \n\n1: Bad  2: Neutral  3: Good",
+         trade.filename, check, state.nickname_or_id(&trade.peer));
          
         let notification = notification_component(&text, "Rate a Peer".to_string());
     
@@ -30,33 +39,28 @@ impl Rating{
 
     /// Event handler for the Rating page.
     pub async fn handle_events<T: FnMut(Tab)>(&mut self, client: &mut Client, key: KeyEvent, mut switch_tab_callback: T) {
-    
-        let mut state = STATE.lock().unwrap();
-        let peer_id = state.current_rating.unwrap();
-    
-        match key.code {
-    
-            // Bad rating
-            KeyCode::Char('1') => {
-                client.update_rating(peer_id, -1).await;
-                state.current_rating = None;
-                switch_tab_callback(Tab::Chat);
-            }
-    
-            // Neutral rating
-            KeyCode::Char('2') => {
-                switch_tab_callback(Tab::Chat);
-                state.current_rating = None;
-            }
-    
-            // Good rating
-            KeyCode::Char('3') => {
-                client.update_rating(peer_id, 1).await;
-                switch_tab_callback(Tab::Chat);
-                state.current_rating = None;
+
+        let rating = match key.code {
+            KeyCode::Char('1') => Some(-1), // Bad rating
+            KeyCode::Char('2') => None,     // Neutral rating
+            KeyCode::Char('3') => Some(1),  // Good rating
+            _ => return,
+        };
+
+        let (trade, done) = {
+            let mut state = STATE.lock().unwrap();
+            if state.trades_to_rate.is_empty() {
+                return;
             }
-    
-            _ => {}
+            (state.trades_to_rate.remove(0), state.trades_to_rate.is_empty())
+        };
+
+        if let Some(rating) = rating {
+            client.update_rating(trade, rating).await;
+        }
+
+        if done {
+            switch_tab_callback(Tab::Chat);
         }
     }
-}
\ No newline at end of file
+}
@@ -6,7 +6,7 @@ use ratatui::{
     widgets::*,
 };
 
-use crate::{network::client::Client, state::STATE, ui::components::{input_component, list_component, Tab}, util};
+use crate::{network::client::Client, state::STATE, ui::components::{input_component, list_component, peer_label, Tab}, util};
 
 /// Represents the currently selected section.
 #[derive(Default, PartialEq)]
@@ -109,22 +109,24 @@ impl RoomMenu {
             KeyCode::Enter => {
                 let mut state = STATE.lock().unwrap();
 
-                state.current_room = match self.selected_section {
-                    Section::Room => state.rooms.get(self.room_list_state.selected().expect("")).expect("").to_string(),
-                    Section::User => {
-                        let own_peer_id = state.peer_id.clone();
-                        let peer_id = state.peers.get(self.user_list_state.selected().expect("")).expect("").to_string();
-                        util::format_dm_key(peer_id, own_peer_id)
-                    }
+                // The selection may no longer exist (eg. the peer has just left)
+                let room = match self.selected_section {
+                    Section::Room => self.room_list_state.selected().and_then(|i| state.rooms.get(i).cloned()),
+                    Section::User => self.user_list_state.selected().and_then(|i| state.named_peers().get(i).cloned()).map(|(peer_id, _)| {
+                        util::format_dm_key(peer_id.to_string(), state.peer_id.clone())
+                    }),
                 };
 
-                switch_tab_callback(Tab::Chat);
+                if let Some(room) = room {
+                    state.current_room = room;
+                    switch_tab_callback(Tab::Chat);
+                }
             }
 
             // Create room based on current input
             KeyCode::BackTab => {
-                let state = STATE.lock().unwrap();
-                if self.input != String::new() && !state.rooms.contains(&self.input) {
+                let exists = STATE.lock().unwrap().rooms.contains(&self.input);
+                if !self.input.is_empty() && !exists {
                     client.create_room(self.input.to_string()).await;
                     self.input = String::new();
                 }
@@ -151,7 +153,7 @@ impl RoomMenu {
 
     /// Fetches available rooms from the global store and formats them in a way to be displayed in the Ratatui UI.
     /// Will display with "- New Messages" if the room has unread messages.
-    fn format_rooms(&self) -> Vec<ListItem> {
+    fn format_rooms(&self) -> Vec<ListItem<'_>> {
 
         let state = STATE.lock().unwrap();
 
@@ -162,7 +164,7 @@ impl RoomMenu {
             if notification == Some(&true) {
                 ListItem::new(format!("{} - New Messages", room.as_str()))
             } else {
-                ListItem::new(format!("{}", room.as_str()))
+                ListItem::new(room.to_string())
             }
         
         }).collect();
@@ -173,27 +175,25 @@ impl RoomMenu {
 
     /// Fetches connected peers from the global store and formats them in a way to be displayed in the Ratatui UI.
     /// Will display with "- New Messages" if the DM has unread messages.
-    fn format_peers(&self) -> Vec<ListItem> {
+    fn format_peers(&self) -> Vec<ListItem<'_>> {
 
         let state = STATE.lock().unwrap();
 
         let peers: Vec<ListItem> = state
-        .peers
-        .iter()
-        .filter_map(|peer_id| {
-            
-            state.nicknames.get(&peer_id.to_string()).map(|nickname| {
+        .named_peers()
+        .into_iter()
+        .map(|(peer_id, nickname)| {
 
-                let own_peer_id = state.peer_id.clone();
-                let dm_key = util::format_dm_key(peer_id.to_string(), own_peer_id);
-                let notification = state.notifications.get(&dm_key);
+            let own_peer_id = state.peer_id.clone();
+            let dm_key = util::format_dm_key(peer_id.to_string(), own_peer_id);
+            let notification = state.notifications.get(&dm_key);
+            let label = peer_label(&nickname, state.peer_health.get(&peer_id.to_string()));
 
-                if notification == Some(&true) {
-                    ListItem::new(format!("{} - New Messages", nickname.as_str()))
-                } else {
-                    ListItem::new(format!("{}", nickname.as_str()))
-                }
-            })
+            if notification == Some(&true) {
+                ListItem::new(format!("{} - New Messages", label))
+            } else {
+                ListItem::new(label)
+            }
         })
         .collect();
 
@@ -111,7 +111,7 @@ impl Router {
         let (room_title, direct_title) = self.calculate_notifications();
         
         // Used to center the Navbar
-        let width = frame.area().width.clone();
+        let width = frame.area().width;
         let title_length = (room_title.len() + direct_title.len() + 10) as u16;
         let padding = if width > title_length { (width - title_length) / 2 } else { 0 };
 
@@ -143,7 +143,7 @@ impl Router {
 
         let state = STATE.lock().unwrap();
 
-        let direct_notifications = state.requests.len() > 0;
+        let direct_notifications = !state.requests.is_empty();
 
         // Ensures notifications aren't shown if the user is currently looking at the chat we received a message for
         let room_notifications = state.notifications
@@ -167,7 +167,7 @@ impl Router {
 
         // Checks if we need to rate a user and will display the rating page instead.
         let state = STATE.lock().unwrap();
-        if state.current_rating.is_some() {
+        if !state.trades_to_rate.is_empty() {
             self.tab = Tab::Rating;
         }
     }
@@ -1,3 +1,29 @@
+use std::{path::{Path, PathBuf}, sync::OnceLock};
+use libp2p::PeerId;
+use rand::RngCore;
+
+/// Name of the directory (inside the data directory) that received files are saved to.
+pub const DOWNLOADS_DIR: &str = "downloads";
+
+/// Data directory chosen on the command line, if any (see set_data_dir).
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Downloads directory chosen in the config or on the command line, if any (see set_downloads_dir).
+static DOWNLOADS: OnceLock<PathBuf> = OnceLock::new();
+
+/// Name given to a received file whose name is unusable (eg. "..").
+const FALLBACK_FILENAME: &str = "download";
+
+/// Longest file name (in bytes) a received file is saved under, as most file systems allow no more than 255.
+const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Start of every DM's room key, so that no room (whose names can't contain it) is ever taken for a DM.
+pub const DM_PREFIX: &str = "dm:";
+
+/// Longest name (in characters) a room can be given.
+const MAX_ROOM_NAME_LENGTH: usize = 64;
+
+
 // Given two PeerId's participating in a DM, formats the room key for the chat to uniquely identify it and ensure consistancy.
 pub fn format_dm_key(peer_id: String, own_peer_id: String) -> String {
 
@@ -7,5 +33,244 @@ pub fn format_dm_key(peer_id: String, own_peer_id: String) -> String {
         (own_peer_id, peer_id)
     };
 
-    format!("{}_{}", bigger_key, smaller_key)
-}
\ No newline at end of file
+    format!("{}{}_{}", DM_PREFIX, bigger_key, smaller_key)
+}
+
+
+// Whether a room key is a DM's (see format_dm_key) rather than a room's.
+pub fn is_dm(room: &str) -> bool {
+    room.starts_with(DM_PREFIX)
+}
+
+
+// Checks a name can be given to a room. As anyone can create a room, names that could be taken for a DM's key (see
+// format_dm_key) aren't allowed: no "_" or ":", and no PeerIds.
+pub fn check_room_name(name: &str) -> Result<(), String> {
+
+    if name.trim().is_empty() {
+        return Err("Room names can't be empty".to_string());
+    }
+    if name.chars().count() > MAX_ROOM_NAME_LENGTH {
+        return Err(format!("Room names can't be longer than {} characters", MAX_ROOM_NAME_LENGTH));
+    }
+    if name.contains(['_', ':']) || name.parse::<PeerId>().is_ok() {
+        return Err(format!("{} can't be used as a room name, as it could be taken for a direct message", name));
+    }
+
+    Ok(())
+}
+
+
+// The other peer in a DM, given its room key (see format_dm_key). None if the room isn't one of our DMs.
+pub fn dm_peer(room: &str, own_peer_id: &str) -> Option<PeerId> {
+
+    let (first, second) = room.strip_prefix(DM_PREFIX)?.split_once('_')?;
+    let other = if first == own_peer_id { second } else if second == own_peer_id { first } else { return None };
+
+    other.parse().ok()
+}
+
+// A random 128-bit id as a hex string, eg. for identifying messages.
+pub fn random_id() -> String {
+
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    hex(&id)
+}
+
+
+// Bytes as a lowercase hex string, eg. for showing a hash.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+// The per-instance directory where Swapbytes keeps everything that isn't the UI: the peer identity, config, logs,
+// control socket, downloads and any persistent state. Running several peers with different data directories keeps
+// them from clobbering each other. Defaults to the per-user directory (see default_data_dir).
+pub fn data_dir() -> PathBuf {
+    DATA_DIR.get().cloned().unwrap_or_else(default_data_dir)
+}
+
+
+// Overrides the data directory (eg. with --data-dir). Only the first call has an effect, so this should be done at
+// startup before anything reads data_dir().
+pub fn set_data_dir(dir: PathBuf) {
+    let _ = DATA_DIR.set(dir);
+}
+
+
+// Where received files are saved. Defaults to the downloads directory inside the data directory.
+pub fn downloads_dir() -> PathBuf {
+    DOWNLOADS.get().cloned().unwrap_or_else(|| data_dir().join(DOWNLOADS_DIR))
+}
+
+
+// Overrides the downloads directory (eg. from the config). Like set_data_dir, only the first call has an effect.
+pub fn set_downloads_dir(dir: PathBuf) {
+    let _ = DOWNLOADS.set(dir);
+}
+
+
+// The name of the file at a path (without the directories leading to it), to send along with the file.
+pub fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| FALLBACK_FILENAME.to_string())
+}
+
+
+// Makes a file name from another peer safe to save under. Only the last component of any path is kept (so "../../.bashrc"
+// becomes "bashrc"), control characters are removed, and leading dots are dropped so the file is neither hidden nor "..".
+pub fn sanitize_filename(name: &str) -> String {
+
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let mut name = name.trim().trim_start_matches('.').to_string();
+
+    while name.len() > MAX_FILENAME_LENGTH {
+        name.pop();
+    }
+
+    if name.is_empty() { FALLBACK_FILENAME.to_string() } else { name }
+}
+
+
+// A path in the given directory for a file with the given name that doesn't already exist, adding a number to the name if
+// it does (eg. "notes (1).pdf").
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let name = Path::new(name);
+    let stem = name.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = name.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+
+    (1..).map(|n| dir.join(format!("{} ({}){}", stem, n, extension))).find(|path| !path.exists()).unwrap()
+}
+
+
+// A number of bytes in the largest unit that keeps it at least 1, eg. "12.3 MB".
+pub fn format_size(bytes: u64) -> String {
+
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, units[unit]) }
+}
+
+
+// The per-user directory where Swapbytes keeps its data by default.
+// Follows the XDG convention of $XDG_DATA_HOME/swapbytes, falling back to ~/.local/share/swapbytes.
+pub fn default_data_dir() -> PathBuf {
+
+    let base = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("."));
+            home.join(".local").join("share")
+        }
+    };
+
+    base.join("swapbytes")
+}
+
+
+// A new empty directory for a test to work in. Also points the data directory somewhere temporary, so tests never touch
+// the user's.
+#[cfg(test)]
+pub fn test_dir() -> PathBuf {
+
+    let root = std::env::temp_dir().join(format!("swapbytes-test-{}", std::process::id()));
+    set_data_dir(root.join("data"));
+    std::fs::create_dir_all(data_dir()).unwrap();
+
+    let dir = root.join(random_id());
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn sanitize_filename_keeps_only_the_name() {
+        assert_eq!(sanitize_filename("../../.bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\notes.pdf"), "notes.pdf");
+        assert_eq!(sanitize_filename("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("  report\n.txt "), "report.txt");
+    }
+
+
+    #[test]
+    fn sanitize_filename_falls_back_for_unusable_names() {
+        assert_eq!(sanitize_filename(".."), FALLBACK_FILENAME);
+        assert_eq!(sanitize_filename(""), FALLBACK_FILENAME);
+        assert_eq!(sanitize_filename("dir/"), FALLBACK_FILENAME);
+        assert_eq!(sanitize_filename("\u{7}"), FALLBACK_FILENAME);
+    }
+
+
+    #[test]
+    fn sanitize_filename_shortens_long_names() {
+        assert_eq!(sanitize_filename(&"a".repeat(300)).len(), MAX_FILENAME_LENGTH);
+
+        // Never cut in the middle of a character
+        let name = sanitize_filename(&"é".repeat(200));
+        assert!(name.len() <= MAX_FILENAME_LENGTH);
+        assert!(name.chars().all(|c| c == 'é'));
+    }
+
+
+    #[test]
+    fn unique_path_numbers_names_already_taken() {
+        let dir = test_dir();
+
+        assert_eq!(unique_path(&dir, "notes.pdf"), dir.join("notes.pdf"));
+
+        fs::write(dir.join("notes.pdf"), "").unwrap();
+        assert_eq!(unique_path(&dir, "notes.pdf"), dir.join("notes (1).pdf"));
+
+        fs::write(dir.join("notes (1).pdf"), "").unwrap();
+        assert_eq!(unique_path(&dir, "notes.pdf"), dir.join("notes (2).pdf"));
+
+        fs::write(dir.join("README"), "").unwrap();
+        assert_eq!(unique_path(&dir, "README"), dir.join("README (1)"));
+    }
+
+
+    #[test]
+    fn dm_keys_are_the_same_for_both_peers() {
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let key = format_dm_key(first.to_string(), second.to_string());
+
+        assert_eq!(key, format_dm_key(second.to_string(), first.to_string()));
+        assert!(is_dm(&key));
+        assert_eq!(dm_peer(&key, &first.to_string()), Some(second));
+        assert_eq!(dm_peer(&key, &second.to_string()), Some(first));
+        assert_eq!(dm_peer(&key, &PeerId::random().to_string()), None);
+    }
+
+
+    #[test]
+    fn room_names_cant_be_taken_for_a_dm() {
+        assert!(check_room_name("cosc473").is_ok());
+        assert!(check_room_name("").is_err());
+        assert!(check_room_name(" ").is_err());
+        assert!(check_room_name("a_b").is_err());
+        assert!(check_room_name("dm:room").is_err());
+        assert!(check_room_name(&PeerId::random().to_string()).is_err());
+        assert!(check_room_name(&"a".repeat(MAX_ROOM_NAME_LENGTH + 1)).is_err());
+    }
+}